@@ -0,0 +1,30 @@
+use reqwest::StatusCode;
+use thiserror::Error;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("server error ({status}): {message}")]
+    Api { status: StatusCode, message: String },
+    #[error("unexpected response status: {0}")]
+    Status(StatusCode),
+    #[error("invalid url: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("websocket error: {0}")]
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+    #[error("malformed message from server: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("server rejected connection: {0}")]
+    Rejected(String),
+    #[error("websocket closed during handshake")]
+    HandshakeClosed,
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        Error::WebSocket(Box::new(e))
+    }
+}
@@ -0,0 +1,107 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::{ready, SinkExt, Stream, StreamExt};
+use hailsplay_protocol::{Capabilities, ClientMessage, Command, RequestId, ServerMessage, PROTOCOL_VERSION};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::Message;
+use url::Url;
+
+use crate::error::{Error, Result};
+
+/// What the server told us about itself during the handshake
+#[derive(Debug, Clone)]
+pub struct Hello {
+    pub protocol: u32,
+    pub server_version: String,
+    pub capabilities: Capabilities,
+}
+
+/// Stream of messages pushed by the server over the websocket
+pub struct Events {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    hello: Hello,
+}
+
+impl Events {
+    pub(crate) async fn connect(url: &Url) -> Result<Events> {
+        let (mut ws, _) = tokio_tungstenite::connect_async(url.as_str()).await?;
+
+        send(&mut ws, &ClientMessage::Hello {
+            protocol: PROTOCOL_VERSION,
+            capabilities: Capabilities::ALL,
+        }).await?;
+
+        let hello = loop {
+            let Some(message) = next_message(&mut ws).await? else {
+                return Err(Error::HandshakeClosed);
+            };
+
+            match message {
+                ServerMessage::Hello { protocol, server_version, capabilities } => {
+                    break Hello { protocol, server_version, capabilities };
+                }
+                ServerMessage::Error { message, .. } => {
+                    return Err(Error::Rejected(message));
+                }
+                _ => {}
+            }
+        };
+
+        Ok(Events { ws, hello })
+    }
+
+    pub fn hello(&self) -> &Hello {
+        &self.hello
+    }
+
+    /// Sends a command to the server. The outcome arrives on the stream as
+    /// an `Ack` or `Error` message carrying the same request id.
+    pub async fn request(&mut self, id: RequestId, command: Command) -> Result<()> {
+        send(&mut self.ws, &ClientMessage::Request { id, command }).await
+    }
+}
+
+impl Stream for Events {
+    type Item = Result<ServerMessage>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let message = match ready!(self.ws.poll_next_unpin(cx)) {
+                None => return Poll::Ready(None),
+                Some(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                Some(Ok(message)) => message,
+            };
+
+            if let Some(result) = parse_message(message) {
+                return Poll::Ready(result.transpose());
+            }
+        }
+    }
+}
+
+async fn send(ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>, message: &ClientMessage) -> Result<()> {
+    let json = serde_json::to_string(message)?;
+    ws.send(Message::Text(json)).await?;
+    Ok(())
+}
+
+async fn next_message(ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) -> Result<Option<ServerMessage>> {
+    while let Some(message) = ws.next().await {
+        if let Some(result) = parse_message(message?) {
+            return result;
+        }
+    }
+
+    Ok(None)
+}
+
+// returns None for frames that carry no server message
+fn parse_message(message: Message) -> Option<Result<Option<ServerMessage>>> {
+    match message {
+        Message::Text(json) => Some(serde_json::from_str(&json).map(Some).map_err(Error::from)),
+        Message::Close(_) => Some(Ok(None)),
+        Message::Binary(_) | Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => None,
+    }
+}
@@ -0,0 +1,184 @@
+//! Async client for the hailsplay HTTP API and websocket event stream.
+
+mod error;
+mod events;
+
+pub use error::{Error, Result};
+pub use events::{Events, Hello};
+pub use hailsplay_protocol as protocol;
+
+use hailsplay_protocol::{AddParams, AddResponse, Lyrics, Metadata, PlayerStatus, Queue, RadioStation, SearchSite, TrackId, TrackInfo, TuneParams};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use url::Url;
+
+#[derive(Clone, Debug)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: Url,
+}
+
+// mirrors the body of error responses from the server
+#[derive(Deserialize)]
+struct ErrorInfo {
+    message: String,
+}
+
+impl Client {
+    /// Creates a client for the hailsplay server at `base_url`,
+    /// eg. `http://127.0.0.1:3000/`
+    pub fn new(base_url: Url) -> Client {
+        Client::with_http(reqwest::Client::new(), base_url)
+    }
+
+    pub fn with_http(http: reqwest::Client, base_url: Url) -> Client {
+        Client { http, base_url }
+    }
+
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    pub async fn queue(&self) -> Result<Queue> {
+        self.send(self.get("api/queue")?).await
+    }
+
+    /// Returns None if there is no track with this id in the queue
+    pub async fn track(&self, id: &TrackId) -> Result<Option<TrackInfo>> {
+        let request = self.get(&format!("api/queue/{}", id.0))?;
+
+        match self.send(request).await {
+            Ok(track) => Ok(Some(track)),
+            Err(Error::Status(StatusCode::NOT_FOUND)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns None if there is no such track, or it has no lyrics
+    pub async fn lyrics(&self, id: &TrackId) -> Result<Option<Lyrics>> {
+        let request = self.get(&format!("api/queue/{}/lyrics", id.0))?;
+
+        match self.send(request).await {
+            Ok(lyrics) => Ok(Some(lyrics)),
+            Err(Error::Status(StatusCode::NOT_FOUND)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Adds media to the queue. For playlist urls, `entries` selects which
+    /// entries to add by index, all entries are added if None.
+    pub async fn add(&self, url: &Url, entries: Option<Vec<u32>>) -> Result<AddResponse> {
+        let params = AddParams { url: Some(url.clone()), query: None, site: SearchSite::default(), start: None, end: None, split_chapters: false, trim_silence: None, sponsorblock_remove: None, entries };
+        self.send(self.post("api/queue")?.json(&params)).await
+    }
+
+    /// Searches `site` for `query` and adds results to the queue. `entries`
+    /// selects results by index as listed by `search`, only the top result
+    /// is added if None.
+    pub async fn add_search(&self, site: SearchSite, query: &str, entries: Option<Vec<u32>>) -> Result<AddResponse> {
+        let params = AddParams { url: None, query: Some(query.to_owned()), site, start: None, end: None, split_chapters: false, trim_silence: None, sponsorblock_remove: None, entries };
+        self.send(self.post("api/queue")?.json(&params)).await
+    }
+
+    pub async fn metadata(&self, url: &Url) -> Result<Metadata> {
+        let request = self.get("api/metadata")?
+            .query(&[("url", url.as_str())]);
+
+        self.send(request).await
+    }
+
+    /// Lists results for `query` on `site` as the entries of the returned
+    /// metadata
+    pub async fn search(&self, site: SearchSite, query: &str) -> Result<Metadata> {
+        let request = self.get("api/metadata")?
+            .query(&[("query", query), ("site", site_param(site))]);
+
+        self.send(request).await
+    }
+
+    pub async fn radio_stations(&self) -> Result<Vec<RadioStation>> {
+        self.send(self.get("api/radio/stations")?).await
+    }
+
+    pub async fn tune(&self, url: &Url) -> Result<()> {
+        let params = TuneParams { url: url.clone() };
+        self.send(self.post("api/radio/tune")?.json(&params)).await
+    }
+
+    pub async fn status(&self) -> Result<PlayerStatus> {
+        self.send(self.get("api/player")?).await
+    }
+
+    pub async fn play(&self) -> Result<()> {
+        self.send(self.post("api/player/play")?).await
+    }
+
+    pub async fn pause(&self) -> Result<()> {
+        self.send(self.post("api/player/pause")?).await
+    }
+
+    pub async fn stop(&self) -> Result<()> {
+        self.send(self.post("api/player/stop")?).await
+    }
+
+    pub async fn skip_next(&self) -> Result<()> {
+        self.send(self.post("api/player/skip-next")?).await
+    }
+
+    pub async fn skip_back(&self) -> Result<()> {
+        self.send(self.post("api/player/skip-back")?).await
+    }
+
+    /// Opens the websocket and completes the hello handshake. The returned
+    /// stream yields every `ServerMessage` the server pushes afterwards.
+    pub async fn events(&self) -> Result<Events> {
+        let mut url = self.base_url.join("ws")?;
+
+        let scheme = match url.scheme() {
+            "https" => "wss",
+            _ => "ws",
+        };
+
+        // only fails when switching between special and non-special
+        // schemes, which http(s) -> ws(s) never does
+        url.set_scheme(scheme).expect("set websocket url scheme");
+
+        Events::connect(&url).await
+    }
+
+    fn get(&self, path: &str) -> Result<RequestBuilder> {
+        Ok(self.http.get(self.base_url.join(path)?))
+    }
+
+    fn post(&self, path: &str) -> Result<RequestBuilder> {
+        Ok(self.http.post(self.base_url.join(path)?))
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T> {
+        let response = request.send().await?;
+        let response = check_status(response).await?;
+        Ok(response.json().await?)
+    }
+}
+
+async fn check_status(response: Response) -> Result<Response> {
+    let status = response.status();
+
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    if let Ok(info) = response.json::<ErrorInfo>().await {
+        return Err(Error::Api { status, message: info.message });
+    }
+
+    Err(Error::Status(status))
+}
+
+fn site_param(site: SearchSite) -> &'static str {
+    match site {
+        SearchSite::Youtube => "youtube",
+        SearchSite::Soundcloud => "soundcloud",
+    }
+}
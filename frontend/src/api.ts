@@ -1,4 +1,4 @@
-import { Metadata, RadioStation, Url, AddResponse } from "./types";
+import { Metadata, RadioStation, Url, AddResponse, Lyrics, TrackId } from "./types";
 
 export async function queueAdd(url: Url, abortSignal: AbortSignal | null): Promise<AddResponse | null> {
     return await post("/api/queue")
@@ -20,6 +20,13 @@ export async function metadata(url: Url, abortSignal: AbortSignal | null): Promi
         .response();
 }
 
+export async function lyrics(trackId: TrackId, abortSignal: AbortSignal | null): Promise<Lyrics | null> {
+    return await get(`/api/queue/${encodeURIComponent(trackId)}/lyrics`)
+        .signal(abortSignal)
+        .allowNotFound()
+        .response();
+}
+
 export async function radioStations(): Promise<RadioStation[]> {
     return await get("/api/radio/stations")
         .response()
@@ -46,10 +53,12 @@ class RequestBuilder {
     _url: string;
     _request: RequestInit & { headers: Headers };
     _query: string;
+    _allowNotFound: boolean;
 
     constructor(method: string, url: string) {
         this._url = url;
         this._query = "";
+        this._allowNotFound = false;
         this._request = { method, headers: new Headers() };
     }
 
@@ -70,6 +79,12 @@ class RequestBuilder {
         return this;
     }
 
+    // resolve to null rather than failing when there's nothing there
+    allowNotFound(): this {
+        this._allowNotFound = true;
+        return this;
+    }
+
     json(body: object): this {
         this._request.headers.set("content-type", "application/json");
         this._request.body = JSON.stringify(body);
@@ -91,6 +106,10 @@ class RequestBuilder {
             }
         }
 
+        if (response.status === 404 && this._allowNotFound) {
+            return null;
+        }
+
         if (response.status >= 400) {
             throw new Error(`${this._request.method} ${this._url} failed: status ${response.status}`);
         }
@@ -125,6 +125,9 @@ function renderPreview(preview: PreviewState) {
                         {preview.metadata.artist ? (
                             <div class={css.previewArtist}>{preview.metadata.artist}</div>
                         ) : null}
+                        {preview.metadata.entries ? (
+                            <div class={css.previewArtist}>{`${preview.metadata.entries.length} tracks`}</div>
+                        ) : null}
                     </div>
                 </div>
             );
@@ -1,9 +1,10 @@
 import css from "./Player.module.css";
 
 import { LiveContext, OptimisticTrack } from "../socket";
-import { useContext } from "preact/hooks";
+import { useContext, useEffect, useMemo, useState } from "preact/hooks";
 import PlayerControls from "./PlayerControls";
-import { PlayerStatus, Queue, QueueItem, TrackInfo } from "../types";
+import { Chapter, DownloadStatus, Lyrics, LyricsLine, PlayerStatus, Queue, QueueItem, TrackId, TrackInfo } from "../types";
+import { lyrics as fetchLyrics } from "../api";
 import { Component, RefObject, createRef } from "preact";
 
 export default function Player() {
@@ -44,6 +45,7 @@ class LoadedPlayer extends Component<LoadedPlayerProps> {
                 <QueueList items={history} scrollSnapStop={true} />
                 <div class={css.player} ref={this.playerRef}>
                     <TrackTransitionContainer />
+                    <CurrentLyrics />
                     <PlayerControls onChangeTrack={() => this.scrollToPlayer()} />
                 </div>
                 <QueueList items={queue} scrollSnapStop={true} />
@@ -136,7 +138,7 @@ function TrackTransitionContainer() {
         return (
             <div class={css.trackTransitionContainer}>
                 <div key="current-track">
-                    <Track track={currentTrack} />
+                    <Track track={currentTrack} playing />
                 </div>
             </div>
         );
@@ -169,7 +171,128 @@ function TrackTransitionContainer() {
     }
 }
 
-function Track(props: { track: TrackInfo }) {
+function secondaryLabel(track: TrackInfo): string | null {
+    if (track.live) {
+        return track.secondaryLabel ? `Live · ${track.secondaryLabel}` : "Live";
+    }
+
+    return track.secondaryLabel;
+}
+
+// the current play position in seconds, which is estimated from the last
+// player status since the server only sends updates on changes. only
+// kept up to date while `wanted`, to save re-rendering for nothing
+function usePlayTime(wanted: boolean): number | null {
+    const live = useContext(LiveContext);
+    const player = live.player.value;
+    const [now, setNow] = useState(Date.now());
+    const receivedAt = useMemo(() => Date.now(), [player]);
+
+    let ticking = wanted && player?.state === "playing";
+
+    useEffect(() => {
+        if (!ticking) {
+            return;
+        }
+
+        let timer = setInterval(() => setNow(Date.now()), 1000);
+        return () => clearInterval(timer);
+    }, [ticking]);
+
+    if (player?.position?.t !== "elapsed") {
+        return null;
+    }
+
+    let time = player.position.time;
+
+    if (player.state === "playing") {
+        time += Math.max(0, now - receivedAt) / 1000;
+    }
+
+    return time;
+}
+
+function useCurrentChapter(track: TrackInfo): Chapter | null {
+    const time = usePlayTime(track.chapters.length > 0);
+
+    if (time === null) {
+        return null;
+    }
+
+    return track.chapters.find(chapter => chapter.start <= time && time < chapter.end) ?? null;
+}
+
+// lyrics of the current track, fetched again as its download progresses
+// since subtitles only turn up once the download starts
+function useCurrentLyrics(): Lyrics | null {
+    const live = useContext(LiveContext);
+    const trackId = live.player.value?.track ?? null;
+    const [loaded, setLoaded] = useState<{ trackId: TrackId, lyrics: Lyrics | null } | null>(null);
+
+    let downloadState = live.queue.value?.items
+        .find(item => item.id === trackId)
+        ?.download?.state ?? null;
+
+    useEffect(() => {
+        if (trackId === null) {
+            return;
+        }
+
+        let controller = new AbortController();
+
+        fetchLyrics(trackId, controller.signal)
+            .then(lyrics => {
+                if (!controller.signal.aborted) {
+                    setLoaded({ trackId, lyrics });
+                }
+            })
+            .catch(error => console.error("fetching lyrics", error));
+
+        return () => controller.abort();
+    }, [trackId, downloadState]);
+
+    // don't show the last track's lyrics while the next track's load
+    return loaded?.trackId === trackId ? loaded.lyrics : null;
+}
+
+function CurrentLyrics() {
+    let lyrics = useCurrentLyrics();
+    let time = usePlayTime(lyrics !== null);
+
+    if (lyrics === null || lyrics.lines.length === 0) {
+        return null;
+    }
+
+    let index = -1;
+    if (time !== null) {
+        while (index + 1 < lyrics.lines.length && lyrics.lines[index + 1].time <= time) {
+            index++;
+        }
+    }
+
+    let current: LyricsLine | undefined = lyrics.lines[index];
+    let next: LyricsLine | undefined = lyrics.lines[index + 1];
+
+    return (
+        <div class={css.lyrics}>
+            <div class={css.lyricsCurrentLine}>
+                {current?.text}
+            </div>
+            <div class={css.lyricsNextLine}>
+                {next?.text}
+            </div>
+        </div>
+    );
+}
+
+function Track(props: { track: TrackInfo, playing?: boolean }) {
+    let chapter = useCurrentChapter(props.track);
+
+    // only the track actually playing has a current chapter
+    let label = props.playing && chapter
+        ? chapter.title
+        : secondaryLabel(props.track);
+
     return (
         <div class={css.trackInfo}>
             <div class={css.coverArtContainer}>
@@ -187,7 +310,7 @@ function Track(props: { track: TrackInfo }) {
                     {props.track.primaryLabel}
                 </div>
                 <div class={css.trackSecondaryLabel}>
-                    {props.track.secondaryLabel}
+                    {label}
                 </div>
             </div>
         </div>
@@ -198,13 +321,13 @@ function QueueList(props: { items: QueueItem[], scrollSnapStop: boolean }) {
     return (
         <>
             {props.items.map(item => (
-                <QueueListItem track={item.track} scrollSnapStop={props.scrollSnapStop} key={item.id} />
+                <QueueListItem track={item.track} download={item.download} scrollSnapStop={props.scrollSnapStop} key={item.id} />
             ))}
         </>
     )
 }
 
-function QueueListItem(props: { track: TrackInfo, scrollSnapStop: boolean }) {
+function QueueListItem(props: { track: TrackInfo, download: DownloadStatus | null, scrollSnapStop: boolean }) {
     let itemClassName = props.scrollSnapStop
         ? `${css.queueItem} ${css.scrollSnapStop}`
         : css.queueItem;
@@ -224,9 +347,28 @@ function QueueListItem(props: { track: TrackInfo, scrollSnapStop: boolean }) {
                     {props.track.primaryLabel}
                 </div>
                 <div class={css.queueItemSecondaryLabel}>
-                    {props.track.secondaryLabel}
+                    {downloadLabel(props.download) ?? secondaryLabel(props.track)}
                 </div>
             </div>
         </div>
     );
 }
+
+function downloadLabel(download: DownloadStatus | null): string | null {
+    switch (download?.state) {
+        case "waiting":
+            return "Waiting to download";
+        case "downloading":
+            if (download.totalBytes) {
+                let percent = Math.floor(download.downloadedBytes / download.totalBytes * 100);
+                return `Downloading ${percent}%`;
+            }
+            return "Downloading";
+        case "retrying":
+            return "Download failed, retrying";
+        case "failed":
+            return download.error ? `Download failed: ${download.error}` : "Download failed";
+        default:
+            return null;
+    }
+}
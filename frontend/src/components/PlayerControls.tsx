@@ -7,7 +7,6 @@ import { useContext } from "preact/hooks";
 
 import { LiveContext } from "../socket";
 import { PlayState, PlayerStatus } from "../types";
-import { post } from "../api";
 import { getNextTrack, getPreviousTrack } from "../player";
 
 import css from "./PlayerControls.module.css";
@@ -58,15 +57,15 @@ export default function PlayerControls(props: { onChangeTrack: () => void }) {
                 } else {
                     setOptimisticState("loading");
                 }
-                await post("/api/player/play").response();
+                await live.request({ t: "play" });
                 break;
             case "pause":
                 setOptimisticState("stopped");
-                await post("/api/player/pause").response();
+                await live.request({ t: "pause" });
                 break;
             case "stop":
                 setOptimisticState("stopped");
-                await post("/api/player/stop").response();
+                await live.request({ t: "stop" });
                 break;
         }
     };
@@ -74,13 +73,13 @@ export default function PlayerControls(props: { onChangeTrack: () => void }) {
     let onSkipNext = async () => {
         setOptimisticState("loading");
         setOptimisticTrack("next", getNextTrack);
-        await post("/api/player/skip-next").response();
+        await live.request({ t: "skip-next" });
     };
 
     let onSkipBack = async() => {
         setOptimisticState("loading");
         setOptimisticTrack("previous", getPreviousTrack);
-        await post("/api/player/skip-back").response();
+        await live.request({ t: "skip-back" });
     };
 
     return (
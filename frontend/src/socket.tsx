@@ -5,13 +5,28 @@ import ReconnectingWebSocket from "reconnecting-websocket";
 // eslint-disable-next-line no-duplicate-imports
 import type { Event, ErrorEvent, CloseEvent } from "reconnecting-websocket";
 
-import { PlayerStatus, Queue, TrackInfo, ServerMessage } from "./types";
+import { PlayerStatus, Queue, TrackInfo, TrackId, ServerMessage, ClientMessage, Command, CommandResponse, RequestId, DownloadStatus } from "./types";
+import { ApiError } from "./api";
+
+// must match PROTOCOL_VERSION in the protocol crate
+const PROTOCOL_VERSION = 6;
+
+type PendingRequest = {
+    resolve: (_: CommandResponse) => void,
+    reject: (_: Error) => void,
+};
 
 export class SocketClient {
     private ws: ReconnectingWebSocket;
+    private nextRequestId: RequestId = 0;
+    private pending = new Map<RequestId, PendingRequest>();
+    // requests are held back until the server has answered our hello
+    private ready = false;
+    private outbox: ClientMessage[] = [];
     public signals = new Live();
 
     constructor() {
+        this.signals.request = (command) => this.request(command);
         this.ws = new ReconnectingWebSocket(websocketUrl().toString());
         this.ws.addEventListener("open", (ev) => this.onopen(ev))
         this.ws.addEventListener("close", (ev) => this.onclose(ev))
@@ -23,14 +38,47 @@ export class SocketClient {
         this.ws.close();
     }
 
+    request(command: Command): Promise<CommandResponse> {
+        let id = this.nextRequestId++;
+        let message: ClientMessage = { t: "request", id, command };
+
+        return new Promise((resolve, reject) => {
+            this.pending.set(id, { resolve, reject });
+
+            if (this.ready) {
+                this.send(message);
+            } else {
+                this.outbox.push(message);
+            }
+        });
+    }
+
+    private send(message: ClientMessage) {
+        this.ws.send(JSON.stringify(message));
+    }
+
     onopen(_: Event) {
         this.signals.reconnecting.value = false;
         console.log("websocket open");
+
+        this.send({
+            t: "hello",
+            protocol: PROTOCOL_VERSION,
+            capabilities: { commands: true, downloadProgress: true },
+        });
     }
 
     onclose(ev: CloseEvent) {
         this.signals.reconnecting.value = true;
         console.log("websocket close: ", ev);
+        this.ready = false;
+
+        // responses to in-flight requests are lost with the connection
+        for (let request of this.pending.values()) {
+            request.reject(new Error("websocket closed before command completed"));
+        }
+        this.pending.clear();
+        this.outbox = [];
     }
 
     onmessage(ev: MessageEvent) {
@@ -39,6 +87,14 @@ export class SocketClient {
         console.log("websocket message:", message);
 
         switch (message.t) {
+            case "hello":
+                this.ready = true;
+                for (let queued of this.outbox) {
+                    this.send(queued);
+                }
+                this.outbox = [];
+                break;
+
             case "queue":
                 this.signals.queue.value = message.queue;
                 break;
@@ -51,9 +107,40 @@ export class SocketClient {
             case "player":
                 this.signals.player.value = message.player;
                 break;
+
+            case "download":
+                this.updateDownload(message.track, message.download);
+                break;
+
+            case "ack":
+                this.pending.get(message.request)?.resolve(message.response);
+                this.pending.delete(message.request);
+                break;
+
+            case "error":
+                if (message.request === null) {
+                    console.log("websocket error message:", message.message);
+                    break;
+                }
+
+                this.pending.get(message.request)?.reject(new ApiError(message.message));
+                this.pending.delete(message.request);
+                break;
         }
     }
 
+    private updateDownload(track: TrackId, download: DownloadStatus) {
+        let queue = this.signals.queue.value;
+        if (queue === null) {
+            return;
+        }
+
+        let items = queue.items.map(item =>
+            item.id === track ? { ...item, download } : item);
+
+        this.signals.queue.value = { ...queue, items };
+    }
+
     onerror(_: ErrorEvent) {
         console.log("websocket error");
     }
@@ -93,6 +180,9 @@ export class Live {
 
     currentTrack = signal<TrackInfo | null>(null);
     optimisticTrack = signal<OptimisticTrack | null>(null);
+
+    request: (_: Command) => Promise<CommandResponse> =
+        () => Promise.reject(new Error("not connected"));
 }
 
 export type OptimisticTrack =
@@ -12,12 +12,23 @@ export interface TuneParams {
 
 export interface AddResponse {
     mpd_id: TrackId;
+    mpd_ids?: TrackId[];
 }
 
 export interface AddParams {
-    url: Url;
+    url?: Url | null;
+    query?: string | null;
+    site?: SearchSite;
+    start?: number | null;
+    end?: number | null;
+    split_chapters?: boolean;
+    trim_silence?: boolean | null;
+    sponsorblock_remove?: string[] | null;
+    entries?: number[] | null;
 }
 
+export type SearchSite = "youtube" | "soundcloud";
+
 export type PlayPosition = { t: "streaming" } | { t: "elapsed"; time: number; duration: number };
 
 export type PlayState = "stopped" | "loading" | "playing";
@@ -26,18 +37,46 @@ export interface PlayerStatus {
     track: TrackId | null;
     state: PlayState;
     position: PlayPosition | null;
+    volume: number | null;
+}
+
+export interface PlaylistEntry {
+    index: number;
+    url: Url;
+    title: string;
+    artist: string | null;
+    thumbnail: Url | null;
+    duration: number | null;
 }
 
 export interface Metadata {
     title: string;
     artist: string | null;
     thumbnail: Url | null;
+    entries?: PlaylistEntry[] | null;
 }
 
 export interface TrackInfo {
     imageUrl: Url | null;
     primaryLabel: string;
     secondaryLabel: string | null;
+    live: boolean;
+    chapters: Chapter[];
+}
+
+export interface Chapter {
+    title: string;
+    start: number;
+    end: number;
+}
+
+export interface Lyrics {
+    lines: LyricsLine[];
+}
+
+export interface LyricsLine {
+    time: number;
+    text: string;
 }
 
 export type TrackId = string;
@@ -46,17 +85,36 @@ export interface Queue {
     items: QueueItem[];
 }
 
+export type DownloadState = "waiting" | "downloading" | "retrying" | "complete" | "failed";
+
+export interface DownloadStatus {
+    state: DownloadState;
+    downloadedBytes: number;
+    totalBytes: number | null;
+    error: string | null;
+}
+
 export interface QueueItem {
     id: TrackId;
     position: number;
     track: TrackInfo;
+    download: DownloadStatus | null;
 }
 
-export type ServerMessage = { t: "queue"; queue: Queue } | { t: "track-change"; track: TrackInfo | null } | { t: "player"; player: PlayerStatus };
+export type CommandResponse = { t: "ok" } | { t: "added"; track: TrackId; tracks: TrackId[] };
+
+export type Command = { t: "play" } | { t: "pause" } | { t: "stop" } | { t: "skip-next" } | { t: "skip-back" } | { t: "seek"; time: number } | { t: "add"; url: Url; entries?: number[] | null } | { t: "remove"; track: TrackId } | { t: "set-volume"; volume: number };
+
+export type RequestId = number;
 
-export interface ClientMessage {
-    t: "ClientMessage";
+export interface Capabilities {
+    commands: boolean;
+    downloadProgress: boolean;
 }
 
+export type ServerMessage = { t: "hello"; protocol: number; server_version: string; capabilities: Capabilities } | { t: "queue"; queue: Queue } | { t: "track-change"; track: TrackInfo | null } | { t: "player"; player: PlayerStatus } | { t: "download"; track: TrackId; download: DownloadStatus } | { t: "ack"; request: RequestId; response: CommandResponse } | { t: "error"; request: RequestId | null; message: string };
+
+export type ClientMessage = { t: "hello"; protocol: number; capabilities: Capabilities } | { t: "request"; id: RequestId; command: Command };
+
 export type Url = string;
 
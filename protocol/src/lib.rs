@@ -3,24 +3,97 @@
 use serde::{Serialize, Deserialize};
 use url::Url;
 
+/// Version of the websocket protocol spoken by this build. Bump this
+/// whenever a change to the messages below would confuse an older peer.
+pub const PROTOCOL_VERSION: u32 = 6;
+
+/// Oldest client protocol version the server still knows how to talk to.
+/// The server sends every client the same messages, so this goes up along
+/// with any change an older client couldn't read.
+pub const MIN_PROTOCOL_VERSION: u32 = 6;
+
 protocol! {
     #[derive(Serialize, Deserialize, Debug)]
     #[serde(tag = "t", rename_all = "kebab-case")]
-    pub struct ClientMessage {}
+    pub enum ClientMessage {
+        Hello { protocol: u32, capabilities: Capabilities },
+        Request { id: RequestId, command: Command },
+    }
 
     #[derive(Serialize, Deserialize, Debug)]
     #[serde(tag = "t", rename_all = "kebab-case")]
     pub enum ServerMessage {
+        Hello { protocol: u32, server_version: String, capabilities: Capabilities },
         Queue { queue: Queue },
         TrackChange { track: Option<TrackInfo> },
         Player { player: PlayerStatus },
+        Download { track: TrackId, download: DownloadStatus },
+        Ack { request: RequestId, response: CommandResponse },
+        Error { request: Option<RequestId>, message: String },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+    #[serde(default, rename_all = "camelCase")]
+    pub struct Capabilities {
+        pub commands: bool,
+        pub download_progress: bool,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct RequestId(pub u32);
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(tag = "t", rename_all = "kebab-case")]
+    pub enum Command {
+        Play,
+        Pause,
+        Stop,
+        SkipNext,
+        SkipBack,
+        Seek { time: f64 },
+        Add {
+            url: Url,
+            #[serde(default)]
+            entries: Option<Vec<u32>>,
+        },
+        Remove { track: TrackId },
+        SetVolume { volume: u8 },
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(tag = "t", rename_all = "kebab-case")]
+    pub enum CommandResponse {
+        Ok,
+        Added { track: TrackId, tracks: Vec<TrackId> },
     }
 
     #[derive(Debug, Serialize, Deserialize)]
     pub struct QueueItem {
         pub id: TrackId,
         pub position: i64,
-        pub track: TrackInfo
+        pub track: TrackInfo,
+        pub download: Option<DownloadStatus>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+    #[serde(rename_all = "camelCase")]
+    pub struct DownloadStatus {
+        pub state: DownloadState,
+        pub downloaded_bytes: u64,
+        pub total_bytes: Option<u64>,
+        pub error: Option<String>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum DownloadState {
+        /// Queued behind other downloads, not started yet
+        Waiting,
+        Downloading,
+        /// Failed, waiting to try again
+        Retrying,
+        Complete,
+        Failed,
     }
 
     #[derive(Debug, Serialize, Deserialize)]
@@ -28,7 +101,7 @@ protocol! {
         pub items: Vec<QueueItem>,
     }
 
-    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
     pub struct TrackId(pub String);
 
     #[derive(Debug, Serialize, Deserialize)]
@@ -37,6 +110,35 @@ protocol! {
         pub image_url: Option<Url>,
         pub primary_label: String,
         pub secondary_label: Option<String>,
+        /// Live streams have no duration and can't be seeked
+        #[serde(default)]
+        pub live: bool,
+        /// Chapters of the track, for showing which one is playing
+        #[serde(default)]
+        pub chapters: Vec<Chapter>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct Chapter {
+        pub title: String,
+        /// Seconds from the start of the track
+        pub start: f64,
+        pub end: f64,
+    }
+
+    /// Lines of lyrics or subtitles, in the order they're sung
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct Lyrics {
+        pub lines: Vec<LyricsLine>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct LyricsLine {
+        /// Seconds from the start of the track, the line lasts until the
+        /// next one starts
+        pub time: f64,
+        /// Empty for gaps between lines
+        pub text: String,
     }
 
     #[derive(Serialize, Deserialize, Debug, Clone, Default)]
@@ -44,6 +146,22 @@ protocol! {
         pub title: String,
         pub artist: Option<String>,
         pub thumbnail: Option<Url>,
+        /// Present when the url refers to a playlist, album or channel,
+        /// and for searches, where the entries are the results
+        #[serde(default)]
+        pub entries: Option<Vec<PlaylistEntry>>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct PlaylistEntry {
+        /// Position of this entry in the playlist, pass this in
+        /// AddParams::entries to choose which entries to add
+        pub index: u32,
+        pub url: Url,
+        pub title: String,
+        pub artist: Option<String>,
+        pub thumbnail: Option<Url>,
+        pub duration: Option<f64>,
     }
 
     #[derive(Debug, Serialize, Deserialize)]
@@ -51,6 +169,7 @@ protocol! {
         pub track: Option<TrackId>,
         pub state: PlayState,
         pub position: Option<PlayPosition>,
+        pub volume: Option<u8>,
     }
 
     #[derive(Debug, Serialize, Deserialize)]
@@ -70,12 +189,58 @@ protocol! {
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct AddParams {
-        pub url: Url,
+        /// Media to add, exactly one of this and `query` must be given
+        #[serde(default)]
+        pub url: Option<Url>,
+        /// Free text to search `site` for instead of adding a url
+        #[serde(default)]
+        pub query: Option<String>,
+        #[serde(default)]
+        pub site: SearchSite,
+        /// Play only part of the media, in seconds from its start. Takes
+        /// the place of any timestamp in the url, eg. `?t=90`
+        #[serde(default)]
+        pub start: Option<f64>,
+        #[serde(default)]
+        pub end: Option<f64>,
+        /// Add one queue entry for each chapter of the media, rather than
+        /// one for the whole thing. Media with silence trimmed or
+        /// SponsorBlock segments cut out isn't split, as its chapters no
+        /// longer line up
+        #[serde(default)]
+        pub split_chapters: bool,
+        /// Cut silence from the start and end of the media when it's
+        /// archived, the server's configured default applies if absent
+        #[serde(default)]
+        pub trim_silence: Option<bool>,
+        /// SponsorBlock categories to cut out of the media, eg.
+        /// "music_offtopic". The server's configured default applies if
+        /// absent, an empty list cuts nothing
+        #[serde(default)]
+        pub sponsorblock_remove: Option<Vec<String>>,
+        /// Which playlist entries to add, all entries are added if absent.
+        /// For searches these pick from the results, which are listed by
+        /// the metadata endpoint, and only the top result is added if absent
+        #[serde(default)]
+        pub entries: Option<Vec<u32>>,
+    }
+
+    /// Site searched by free text queries
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum SearchSite {
+        #[default]
+        Youtube,
+        Soundcloud,
     }
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct AddResponse {
+        /// The first item added to the queue
         pub mpd_id: TrackId,
+        /// Every item added to the queue, in queue order
+        #[serde(default)]
+        pub mpd_ids: Vec<TrackId>,
     }
 
     #[derive(Serialize, Deserialize, Debug)]
@@ -92,3 +257,36 @@ protocol! {
     }
 
 }
+
+impl Capabilities {
+    /// Capabilities supported by this build of the protocol crate.
+    pub const ALL: Capabilities = Capabilities {
+        commands: true,
+        download_progress: true,
+    };
+
+    /// Capabilities supported by both sides of a connection.
+    pub fn intersect(self, other: Capabilities) -> Capabilities {
+        Capabilities {
+            commands: self.commands && other.commands,
+            download_progress: self.download_progress && other.download_progress,
+        }
+    }
+}
+
+impl TrackInfo {
+    /// The chapter playing `time` seconds into the track
+    pub fn chapter_at(&self, time: f64) -> Option<&Chapter> {
+        self.chapters.iter()
+            .find(|chapter| chapter.start <= time && time < chapter.end)
+    }
+}
+
+impl Lyrics {
+    /// The line being sung `time` seconds into the track
+    pub fn line_at(&self, time: f64) -> Option<&LyricsLine> {
+        self.lines.iter()
+            .take_while(|line| line.time <= time)
+            .last()
+    }
+}
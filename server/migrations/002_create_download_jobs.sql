@@ -0,0 +1,7 @@
+CREATE TABLE download_jobs (
+    id INTEGER NOT NULL PRIMARY KEY,
+    stream_uuid TEXT NOT NULL UNIQUE,
+    url TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    metadata TEXT NOT NULL
+);
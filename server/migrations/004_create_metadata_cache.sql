@@ -0,0 +1,5 @@
+CREATE TABLE metadata_cache (
+    url TEXT NOT NULL PRIMARY KEY,
+    fetched_at TEXT NOT NULL,
+    info TEXT NOT NULL
+);
@@ -0,0 +1,4 @@
+ALTER TABLE download_jobs ADD COLUMN clip_start INTEGER NULL;
+ALTER TABLE download_jobs ADD COLUMN clip_end INTEGER NULL;
+ALTER TABLE archived_media ADD COLUMN clip_start INTEGER NULL;
+ALTER TABLE archived_media ADD COLUMN clip_end INTEGER NULL;
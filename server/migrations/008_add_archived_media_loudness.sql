@@ -0,0 +1,2 @@
+ALTER TABLE archived_media ADD COLUMN track_gain REAL NULL;
+ALTER TABLE archived_media ADD COLUMN track_peak REAL NULL;
@@ -0,0 +1 @@
+ALTER TABLE download_jobs ADD COLUMN processing TEXT NOT NULL DEFAULT '{}';
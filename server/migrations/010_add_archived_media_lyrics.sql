@@ -0,0 +1 @@
+ALTER TABLE archived_media ADD COLUMN lyrics_path TEXT NULL;
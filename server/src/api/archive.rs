@@ -1,20 +1,29 @@
-use std::{collections::HashMap, sync::{Arc, Mutex}, path::{PathBuf, Path}};
+use std::{cmp, collections::HashMap, io, sync::{Arc, Mutex}, path::{Path, PathBuf}, time::Duration};
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use derive_more::{Display, FromStr};
-use mime::Mime;
+use futures::future;
+use hailsplay_protocol::{DownloadState, DownloadStatus, LyricsLine};
 use rusqlite::OptionalExtension;
 use serde::Deserialize;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
 use url::Url;
 use uuid::Uuid;
 use thiserror::Error;
 
 use crate::{api::asset, ytdlp::DownloadError};
-use crate::config::Config;
+use crate::clip::Clip;
+use crate::config::{self, Config};
 use crate::db::Pool;
 use crate::db::archive::{self, ArchiveRecord, ArchiveRecordId};
-use crate::fs::WorkingDirectory;
-use crate::ytdlp::{self, Metadata};
+use crate::db::download::{self, DownloadJob, DownloadSource};
+use crate::direct;
+use crate::fs::{self, SharedFile, WorkingDirectory};
+use crate::loudness;
+use crate::lyrics::{self, LyricsError};
+use crate::postprocess::{self, Processing};
+use crate::ytdlp::{self, Metadata, Progress};
 
 #[derive(Clone)]
 pub struct Archive {
@@ -23,19 +32,31 @@ pub struct Archive {
 
 #[derive(Error, Debug)]
 pub enum AddUrlError {
-    #[error("io error: {0}")]
+    #[error("serializing metadata: {0}")]
+    SerializeMetadata(#[from] serde_json::Error),
+    #[error("saving download job: {0}")]
+    Database(#[from] rusqlite::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum ResumeError {
+    #[error("loading download jobs: {0}")]
+    Database(#[from] rusqlite::Error),
+    #[error("cleaning working directory: {0}")]
     Io(#[from] std::io::Error),
-    #[error("yt-dlp error: {0}")]
-    YtDlp(ytdlp::DownloadError),
 }
 
 impl Archive {
-    pub fn new(database: Pool, working: WorkingDirectory, http: reqwest::Client) -> Archive {
+    pub fn new(database: Pool, working: WorkingDirectory, http: reqwest::Client, config: &Config) -> Archive {
         let shared = Shared {
             database,
             working,
             http,
+            ytdlp: config.ytdlp.clone(),
+            archive_dir: config.storage.archive.clone(),
+            postprocess: config.postprocess.clone(),
             locked: Mutex::default(),
+            downloads_changed: watch::channel(()).0,
         };
 
         Archive { shared: Arc::new(shared) }
@@ -49,7 +70,7 @@ impl Archive {
 
         // database records always take precedence over in-process state
         if let Some((id, record)) = record {
-            return Ok(Some(RecordKind::Archive(id, record)));
+            return Ok(Some(RecordKind::Archive(id, Box::new(record))));
         }
 
         // check locked state next
@@ -62,36 +83,409 @@ impl Archive {
         Ok(None)
     }
 
-    pub async fn add_url(&self, url: &Url) -> Result<RecordKind, AddUrlError> {
-        let id = MediaStreamId(uuid::Uuid::new_v4());
-        let dir = self.shared.working.create_dir(&id.to_string()).await?;
-        let dir = dir.into_shared();
+    /// Returns None for media which is not being downloaded by this process
+    pub fn download_status(&self, id: MediaStreamId) -> Option<DownloadStatus> {
+        let locked = self.shared.locked.lock().unwrap();
+        locked.media.get(&id).map(|record| record.download_status())
+    }
 
-        let download = ytdlp::start_download(dir, url).await
-            .map_err(AddUrlError::YtDlp)?;
+    /// Notified whenever the progress or state of any download changes
+    pub fn watch_downloads(&self) -> watch::Receiver<()> {
+        self.shared.downloads_changed.subscribe()
+    }
 
-        let record = Arc::new(MemoryRecord {
-            id,
+    /// Ids of all media currently being downloaded by this process
+    pub fn active_downloads(&self) -> Vec<MediaStreamId> {
+        let locked = self.shared.locked.lock().unwrap();
+        locked.media.keys().copied().collect()
+    }
+
+    /// Ids of downloads which have failed, out of those added before
+    /// `added_before`
+    pub fn failed_downloads(&self, added_before: DateTime<Utc>) -> Vec<MediaStreamId> {
+        let locked = self.shared.locked.lock().unwrap();
+        locked.media.values()
+            .filter(|record| record.created_at < added_before)
+            .filter(|record| matches!(*record.download.borrow(), DownloadSlot::Failed(_)))
+            .map(|record| record.id)
+            .collect()
+    }
+
+    /// Sets the order in which waiting downloads are started, most urgent
+    /// first. Downloads not mentioned are started after these, oldest first.
+    pub fn prioritize(&self, order: impl IntoIterator<Item = MediaStreamId>) {
+        let mut locked = self.shared.locked.lock().unwrap();
+        locked.priority = order.into_iter()
+            .enumerate()
+            .map(|(rank, id)| (id, rank))
+            .collect();
+    }
+
+    /// Stops a waiting or in-progress download and removes its working
+    /// directory. Returns false if there is no such download, or if it has
+    /// already finished and is being archived.
+    pub async fn cancel(&self, id: MediaStreamId) -> Result<bool, rusqlite::Error> {
+        if !self.cancel_record(id) {
+            return Ok(false);
+        }
+
+        self.shared.database.with(|conn| {
+            download::delete_job(conn, &id)
+        }).await?;
+
+        Ok(true)
+    }
+
+    fn cancel_record(&self, id: MediaStreamId) -> bool {
+        let mut locked = self.shared.locked.lock().unwrap();
+
+        let finished = match locked.media.get(&id) {
+            Some(record) => record.download().is_some_and(|download| {
+                matches!(download.complete.peek(), Some(Ok(Ok(()))))
+            }),
+            None => return false,
+        };
+
+        if finished {
+            return false;
+        }
+
+        let record = locked.remove(id).expect("record present");
+
+        log::info!("cancelling download: {}", record.url);
+
+        if let Some(index) = locked.waiting.iter().position(|waiting| waiting.id == id) {
+            // never started, so there's nothing to clean up
+            locked.waiting.remove(index);
+            record.download.send_replace(DownloadSlot::Failed(DownloadError::Cancelled));
+        } else {
+            // the download task notices this and cleans up after yt-dlp
+            record.cancel.cancel();
+
+            if let Some(download) = record.download() {
+                download.cancel();
+            }
+        }
+
+        drop(locked);
+        self.shared.downloads_changed.send_replace(());
+
+        true
+    }
+
+    /// Queues `url` for download. `metadata` describes the media until
+    /// the download starts and yt-dlp reports the real thing.
+    /// Media which has already been archived or is being downloaded is
+    /// reused rather than downloaded again, as long as it's the same clip.
+    /// `processing` only applies when the media is newly downloaded.
+    pub async fn add_url(
+        &self,
+        url: &Url,
+        metadata: Metadata,
+        source: DownloadSource,
+        clip: Clip,
+        processing: Processing,
+    ) -> Result<RecordKind, AddUrlError> {
+        let mut urls = vec![url.clone()];
+
+        if let Some(canonical) = &metadata.webpage_url {
+            if canonical != url {
+                urls.push(canonical.clone());
+            }
+        }
+
+        if let Some(record) = self.find_existing(&urls, clip).await? {
+            log::info!("reusing existing media for {url}: {}", record.stream_id());
+            return Ok(record);
+        }
+
+        let job = DownloadJob {
+            stream_uuid: MediaStreamId(uuid::Uuid::new_v4()),
             url: url.clone(),
-            download: Arc::new(download),
-        });
+            created_at: Utc::now(),
+            metadata: serde_json::to_value(&metadata)?,
+            error: None,
+            source,
+            clip,
+            processing,
+        };
+
+        // persist the job before anyone learns its stream url, so that
+        // the stream can be served again after a restart
+        self.shared.database.with(|conn| {
+            download::insert_job(conn, &job)
+        }).await?;
+
+        let record = self.insert_record(job, metadata, DownloadSlot::Waiting);
+        schedule(&self.shared);
 
-        tokio::task::spawn({
-            let shared = self.shared.clone();
-            let record = record.clone();
-            async move {
-                let result = archive_once_download_complete(shared, record).await;
-                if let Err(e) = result {
-                    log::error!("error archiving media, not saving: {e:?}");
+        Ok(RecordKind::Memory(record))
+    }
+
+    // looks up media by original or canonical url
+    async fn find_existing(&self, urls: &[Url], clip: Clip) -> Result<Option<RecordKind>, rusqlite::Error> {
+        let archived = self.shared.database.with(|conn| {
+            for url in urls {
+                if let Some((id, record)) = archive::load_by_canonical_url(conn, url, clip).optional()? {
+                    return Ok(Some((id, record)));
                 }
             }
+
+            Ok::<_, rusqlite::Error>(None)
+        }).await?;
+
+        // database records always take precedence over in-process state
+        if let Some((id, record)) = archived {
+            return Ok(Some(RecordKind::Archive(id, Box::new(record))));
+        }
+
+        let locked = self.shared.locked.lock().unwrap();
+
+        let in_flight = urls.iter()
+            .filter_map(|url| locked.media_by_url.get(&(url.clone(), clip)))
+            .filter_map(|id| locked.media.get(id))
+            // failed downloads deserve another go
+            .find(|record| !matches!(record.download_status().state, DownloadState::Failed));
+
+        Ok(in_flight.map(|record| RecordKind::Memory(record.clone())))
+    }
+
+    /// Restarts the downloads left unfinished by a previous run of the
+    /// server. Must be called before any media streams are served.
+    pub async fn resume(&self) -> Result<(), ResumeError> {
+        let jobs = self.shared.database.with(|conn| {
+            download::all_jobs(conn)
+        }).await?;
+
+        // partial downloads can't be picked up where they left off, so
+        // clear out everything the previous run left in the working dir
+        let mut entries = tokio::fs::read_dir(self.shared.working.path()).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            // named <stream id>.<attempt>
+            let is_download = entry.file_name().to_str()
+                .and_then(|name| name.split('.').next())
+                .is_some_and(|id| id.parse::<MediaStreamId>().is_ok());
+
+            if is_download {
+                remove_working_dir(&entry.path()).await;
+            }
+        }
+
+        for mut job in jobs {
+            let metadata = job.parse_metadata().unwrap_or_else(|e| {
+                log::warn!("parsing metadata of download job {}: {e:?}", job.stream_uuid);
+                Metadata::default()
+            });
+
+            // jobs which gave up last time stay failed rather than being
+            // retried on every restart
+            let slot = match job.error.take() {
+                Some(error) => DownloadSlot::Failed(DownloadError::Recorded(error)),
+                None => {
+                    log::info!("resuming download: {}", job.url);
+                    DownloadSlot::Waiting
+                }
+            };
+
+            self.insert_record(job, metadata, slot);
+        }
+
+        schedule(&self.shared);
+
+        Ok(())
+    }
+
+    fn insert_record(&self, job: DownloadJob, metadata: Metadata, slot: DownloadSlot) -> Arc<MemoryRecord> {
+        let waiting = matches!(slot, DownloadSlot::Waiting);
+        let (id, url, clip) = (job.stream_uuid, job.url, job.clip);
+
+        let removes_segments = job.source == DownloadSource::YtDlp
+            && job.processing.sponsorblock_remove(&self.shared.ytdlp.options_for(&url)).is_some();
+
+        let cuts_media = removes_segments || job.processing.trim_silence(&self.shared.postprocess);
+
+        let record = Arc::new(MemoryRecord {
+            id,
+            url: url.clone(),
+            created_at: job.created_at,
+            source: job.source,
+            clip,
+            processing: job.processing,
+            cuts_media,
+            preview: metadata,
+            cancel: CancellationToken::new(),
+            download: watch::channel(slot).0,
         });
 
         let mut locked = self.shared.locked.lock().unwrap();
-        locked.media_by_url.insert(url.clone(), id);
+        locked.media_by_url.insert((url, clip), id);
         locked.media.insert(id, record.clone());
 
-        Ok(RecordKind::Memory(record))
+        if let Some(canonical) = &record.preview.webpage_url {
+            locked.media_by_url.insert((canonical.clone(), clip), id);
+        }
+
+        if waiting {
+            locked.waiting.push(record.clone());
+        }
+
+        record
+    }
+}
+
+// downloads failing with transient errors are retried with exponential
+// backoff, starting at RETRY_BACKOFF, until MAX_ATTEMPTS have been made
+const MAX_ATTEMPTS: u32 = 4;
+const RETRY_BACKOFF: Duration = Duration::from_secs(5);
+
+// starts waiting downloads until the concurrency limit is reached
+fn schedule(shared: &Arc<Shared>) {
+    let limit = cmp::max(1, shared.ytdlp.concurrent_downloads);
+    let mut locked = shared.locked.lock().unwrap();
+
+    while locked.running < limit {
+        let Some(record) = locked.next_waiting() else {
+            break;
+        };
+
+        locked.running += 1;
+        tokio::task::spawn(run_download(shared.clone(), record));
+    }
+}
+
+async fn run_download(shared: Arc<Shared>, record: Arc<MemoryRecord>) {
+    match download_with_retries(&shared, &record).await {
+        Ok(()) => {}
+        Err(ArchiveError::DownloadFailed(DownloadError::Cancelled)) => {}
+        Err(e) => {
+            log::error!("error archiving media, not saving: {e:?}");
+
+            // failures after the download itself (eg. moving it into the
+            // archive) leave the slot looking complete, so mark it failed
+            // for the queue to show
+            if !matches!(*record.download.borrow(), DownloadSlot::Failed(_)) {
+                let error = DownloadError::Recorded(e.to_string());
+                record.download.send_replace(DownloadSlot::Failed(error));
+            }
+
+            // remember why, so the failure survives a restart
+            let result = shared.database.with(|conn| {
+                download::set_job_error(conn, &record.id, &e.to_string())
+            }).await;
+
+            if let Err(e) = result {
+                log::warn!("saving download job error: {e:?}");
+            }
+        }
+    }
+
+    shared.locked.lock().unwrap().running -= 1;
+    schedule(&shared);
+
+    // let watchers see the final state of the download
+    shared.downloads_changed.send_replace(());
+}
+
+async fn download_with_retries(shared: &Arc<Shared>, record: &Arc<MemoryRecord>) -> Result<(), ArchiveError> {
+    let mut attempt = 1;
+
+    loop {
+        let e = match download_and_archive(shared, record, attempt).await {
+            Ok(()) => return Ok(()),
+            Err(ArchiveError::DownloadFailed(e)) if e.is_transient() && attempt < MAX_ATTEMPTS => e,
+            Err(ArchiveError::DownloadFailed(e)) => {
+                record.download.send_replace(DownloadSlot::Failed(e.clone()));
+                return Err(ArchiveError::DownloadFailed(e));
+            }
+            Err(e) => return Err(e),
+        };
+
+        let delay = RETRY_BACKOFF * 2u32.pow(attempt - 1);
+        log::warn!("download attempt {attempt} failed, retrying in {delay:?}: {}: {e}", record.url);
+
+        record.download.send_replace(DownloadSlot::Retrying(e));
+        shared.downloads_changed.send_replace(());
+
+        tokio::select! {
+            () = tokio::time::sleep(delay) => {}
+            () = record.cancel.cancelled() => {
+                return Err(ArchiveError::DownloadFailed(DownloadError::Cancelled));
+            }
+        }
+
+        attempt += 1;
+    }
+}
+
+async fn download_and_archive(shared: &Arc<Shared>, record: &Arc<MemoryRecord>, attempt: u32)
+    -> Result<(), ArchiveError>
+{
+    log::info!("starting download: {}", record.url);
+
+    let download = start_download(shared, record, attempt).await
+        .map(Arc::new)
+        .map_err(ArchiveError::DownloadFailed)?;
+
+    record.download.send_replace(DownloadSlot::Started(download.clone()));
+    shared.downloads_changed.send_replace(());
+
+    // cancelled between yt-dlp starting and the slot being filled
+    if record.cancel.is_cancelled() {
+        download.cancel();
+    }
+
+    let progress = notify_progress(shared.clone(), download.progress.clone());
+    let archive = archive_once_download_complete(shared.clone(), record.clone(), download.clone());
+    let ((), result) = future::join(progress, archive).await;
+
+    // once archived the files belong to the archive, otherwise they're
+    // just leftovers of a failed attempt
+    if let Err(ArchiveError::DownloadFailed(_)) = &result {
+        remove_working_dir(download.dir.path()).await;
+    }
+
+    result
+}
+
+async fn start_download(shared: &Shared, record: &MemoryRecord, attempt: u32)
+    -> Result<ytdlp::DownloadHandle, DownloadError>
+{
+    // each attempt gets a fresh directory, streams of a failed attempt may
+    // still hold on to its files for a while
+    let dir = shared.working.create_dir(&format!("{}.{attempt}", record.id)).await
+        .map_err(DownloadError::working_dir)?
+        .into_shared();
+
+    let start = async {
+        match record.source {
+            DownloadSource::YtDlp => ytdlp::start_download(&shared.ytdlp, dir.clone(), &record.url, record.clip, &record.processing).await,
+            DownloadSource::Direct => direct::start_download(&shared.http, dir.clone(), &record.url, &record.preview).await,
+        }
+    };
+
+    let result = tokio::select! {
+        result = start => result,
+        () = record.cancel.cancelled() => Err(DownloadError::Cancelled),
+    };
+
+    // yt-dlp was killed when its start future was dropped above
+    if result.is_err() {
+        remove_working_dir(dir.path()).await;
+    }
+
+    result
+}
+
+async fn remove_working_dir(dir: &Path) {
+    if let Err(e) = tokio::fs::remove_dir_all(dir).await {
+        log::warn!("removing working directory {}: {e:?}", dir.display());
+    }
+}
+
+async fn notify_progress(shared: Arc<Shared>, mut progress: watch::Receiver<Progress>) {
+    while progress.changed().await.is_ok() {
+        shared.downloads_changed.send_replace(());
     }
 }
 
@@ -107,19 +501,30 @@ enum ArchiveError {
     InsertThumbnail(rusqlite::Error),
     #[error("failed to save media record to database: {0}")]
     InsertArchiveRecord(rusqlite::Error),
+    #[error("failed to remove download job from database: {0}")]
+    DeleteDownloadJob(rusqlite::Error),
+    #[error("failed to move download into archive: {0}")]
+    Promote(io::Error),
 }
 
 async fn archive_once_download_complete(
     shared: Arc<Shared>,
     record: Arc<MemoryRecord>,
+    download: Arc<ytdlp::DownloadHandle>,
 ) -> Result<(), ArchiveError> {
-    let complete = record.download.complete.clone();
+    let complete = download.complete.clone();
 
     complete.await
         .map_err(|_| ArchiveError::DownloadTaskFailed)?
         .map_err(ArchiveError::DownloadFailed)?;
 
-    let metadata = record.metadata();
+    // cancel_record only refuses downloads which have already finished, so
+    // it may have got in just before this one did
+    if record.cancel.is_cancelled() {
+        return Err(ArchiveError::DownloadFailed(DownloadError::Cancelled));
+    }
+
+    let metadata = &download.metadata;
 
     log::info!("finished downloading, now processing: {}", record.url);
 
@@ -141,67 +546,198 @@ async fn archive_once_download_complete(
         None => None,
     };
 
-    let metadata_value = serde_json::to_value(metadata)
+    let metadata_value = serde_json::to_value(record.metadata())
         .map_err(ArchiveError::SerializeMetadata)?;
 
-    shared.database.with(|conn| {
+    let record_id = record.id;
+
+    let path = promote(&shared.archive_dir, record.id, &download).await
+        .map_err(ArchiveError::Promote)?;
+
+    let archived_file = shared.archive_dir.join(&path);
+
+    // processing is best effort, the media is still worth keeping without
+    let trimmed = match record.processing.trim_silence(&shared.postprocess) {
+        true => postprocess::trim_silence(&shared.postprocess, &archived_file).await
+            .unwrap_or_else(|e| {
+                log::warn!("trimming silence from {path}: {e}");
+                None
+            }),
+        false => None,
+    };
+
+    // subtitles are timed to the original media, lyrics to what's archived
+    let lyrics = match &download.subtitles {
+        Some(subtitles) => {
+            let clip = match trimmed {
+                Some((start, end)) => record.clip.narrow(start, end),
+                None => record.clip,
+            };
+
+            convert_lyrics(&shared.archive_dir, &path, subtitles, clip).await
+        }
+        None => None,
+    };
+
+    // the normalize-archive tool can have another go at loudness later
+    let loudness = match shared.postprocess.normalize_loudness {
+        true => match loudness::normalize(&shared.postprocess.ffmpeg, &archived_file).await {
+            Ok(loudness) => Some(loudness),
+            Err(e) => {
+                log::warn!("measuring loudness of {path}: {e}");
+                None
+            }
+        },
+        false => None,
+    };
+
+    let result = shared.database.with(|conn| {
         let thumbnail_id = thumbnail
             .map(|thumbnail| thumbnail.insert(conn))
             .transpose()
             .map_err(ArchiveError::InsertThumbnail)?;
 
         let record = ArchiveRecord {
-            filename: record.download.filename(),
+            filename: path,
             canonical_url,
             archived_at: Utc::now(),
             stream_uuid: record.id,
             thumbnail_id,
             metadata: metadata_value,
+            clip: record.clip,
+            track_gain: loudness.map(|loudness| loudness.track_gain()),
+            track_peak: loudness.map(|loudness| loudness.track_peak()),
+            lyrics,
         };
 
         archive::insert_media_record(conn, record)
-            .map_err(ArchiveError::InsertArchiveRecord)
-    }).await?;
+            .map_err(ArchiveError::InsertArchiveRecord)?;
 
-    let mut locked = shared.locked.lock().unwrap();
-    locked.media.remove(&record.id);
-    locked.media_by_url.remove(&record.url);
+        download::delete_job(conn, &record_id)
+            .map_err(ArchiveError::DeleteDownloadJob)
+    }).await;
+
+    if let Err(e) = result {
+        // don't leave files behind that no archive record points to
+        let dir = shared.archive_dir.join(record.id.to_string());
+        if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+            log::warn!("removing archive directory {}: {e:?}", dir.display());
+        }
+
+        return Err(e);
+    }
+
+    shared.locked.lock().unwrap().remove(record.id);
 
     log::info!("successfully downloaded and archived url: {}", record.url);
 
     Ok(())
 }
 
-pub enum RecordKind {
-    Memory(Arc<MemoryRecord>),
-    Archive(ArchiveRecordId, ArchiveRecord),
-}
+// moves the downloaded files out of the working directory, where they're
+// deleted once the download is dropped, and into their own directory in
+// the archive. returns the path of the media relative to the archive
+async fn promote(archive_dir: &Path, id: MediaStreamId, download: &ytdlp::DownloadHandle)
+    -> io::Result<String>
+{
+    let relative_dir = PathBuf::from(id.to_string());
+    let dir = archive_dir.join(&relative_dir);
+    tokio::fs::create_dir_all(&dir).await?;
 
-impl RecordKind {
-    pub fn content_type(&self) -> Mime {
-        let path = match self {
-            RecordKind::Archive(_, record) => Path::new(&record.filename),
-            RecordKind::Memory(record) => record.download.file.path(),
+    // the download is linked rather than moved, so that it can be served
+    // from the working directory right up until the archive record exists.
+    // processing replaces the archived file rather than writing through
+    // the link, leaving the download as it was
+    let media = relative_dir.join(download.filename());
+    fs::link_file(download.file().path(), &archive_dir.join(&media)).await?;
+
+    // the info json and thumbnail are nice to have, but the media is
+    // all we need to serve the stream
+    let extras = std::iter::once(&download.metadata_file)
+        .chain(download.thumbnail.as_ref())
+        .chain(download.subtitles.as_ref());
+
+    for file in extras {
+        let Some(filename) = file.path().file_name() else {
+            continue;
         };
 
-        crate::mime::from_path(path)
+        if let Err(e) = fs::link_file(file.path(), &dir.join(filename)).await {
+            log::warn!("linking {} into archive: {e:?}", file.path().display());
+        }
+    }
+
+    let moved = tokio::fs::metadata(archive_dir.join(&media)).await?;
+
+    if !moved.is_file() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "media missing from archive after linking"));
+    }
+
+    Ok(media.to_string_lossy().into_owned())
+}
+
+// lyrics are nice to have too, returns the path of the LRC file made from
+// the subtitles moved into the archive alongside `media`
+async fn convert_lyrics(archive_dir: &Path, media: &str, subtitles: &SharedFile, clip: Clip) -> Option<String> {
+    let subtitles = archive_dir.join(media).with_file_name(subtitles.path().file_name()?);
+    let lyrics = Path::new(media).with_extension("lrc");
+
+    match lyrics::convert(&subtitles, &archive_dir.join(&lyrics), clip).await {
+        Ok(true) => Some(lyrics.to_string_lossy().into_owned()),
+        Ok(false) => None,
+        Err(e) => {
+            log::warn!("converting {} to lyrics: {e}", subtitles.display());
+            None
+        }
     }
+}
 
-    pub fn disk_path(&self, config: &Config) -> PathBuf {
+pub enum RecordKind {
+    Memory(Arc<MemoryRecord>),
+    Archive(ArchiveRecordId, Box<ArchiveRecord>),
+}
+
+impl RecordKind {
+    /// Falls back to the url for downloads which haven't started yet
+    pub fn filename(&self) -> String {
         match self {
             RecordKind::Archive(_, record) => {
-                config.storage.archive.join(&record.filename)
-            }
-            RecordKind::Memory(record) => {
-                record.download.file.path().to_owned()
+                // archived media lives in a directory of its own
+                let path = Path::new(&record.filename);
+                path.file_name().unwrap_or(path.as_os_str()).to_string_lossy().into_owned()
             }
+            RecordKind::Memory(record) => match record.download() {
+                Some(download) => download.filename(),
+                None => record.url.to_string(),
+            },
         }
     }
 
-    pub fn filename(&self) -> String {
+    pub fn clip(&self) -> Clip {
         match self {
-            RecordKind::Archive(_, record) => record.filename.clone(),
-            RecordKind::Memory(record) => record.download.filename(),
+            RecordKind::Archive(_, record) => record.clip,
+            RecordKind::Memory(record) => record.clip,
+        }
+    }
+
+    /// Lyrics timed to the downloaded media, from the archive or from the
+    /// subtitles of a download in progress
+    pub async fn lyrics(&self, archive_dir: &Path) -> Result<Option<Vec<LyricsLine>>, LyricsError> {
+        match self {
+            RecordKind::Archive(_, record) => match &record.lyrics {
+                Some(path) => lyrics::read(&archive_dir.join(path)).await.map(Some),
+                None => Ok(None),
+            },
+            RecordKind::Memory(record) => {
+                // holding on to the file keeps it from being cleaned up
+                // under us if the download finishes meanwhile
+                let Some(subtitles) = record.download().and_then(|download| download.subtitles.clone()) else {
+                    return Ok(None);
+                };
+
+                let lines = lyrics::read(subtitles.path()).await?;
+                Ok(Some(lyrics::in_clip(&lines, record.clip)))
+            }
         }
     }
 
@@ -224,7 +760,7 @@ impl RecordKind {
                     .map_err(|error| MetadataParseError { id: *id, error })
             }
             RecordKind::Memory(record) => {
-                Ok(record.metadata().clone())
+                Ok(record.metadata())
             }
         }
     }
@@ -246,24 +782,507 @@ struct Shared {
     database: Pool,
     working: WorkingDirectory,
     http: reqwest::Client,
+    ytdlp: config::YtDlp,
+    archive_dir: PathBuf,
+    postprocess: config::PostProcess,
     locked: Mutex<Locked>,
+    downloads_changed: watch::Sender<()>,
 }
 
 #[derive(Default)]
 struct Locked {
-    media_by_url: HashMap<Url, MediaStreamId>,
+    // the same url may be downloaded several times as different clips
+    media_by_url: HashMap<(Url, Clip), MediaStreamId>,
     media: HashMap<MediaStreamId, Arc<MemoryRecord>>,
+    // downloads which haven't started yet, oldest first
+    waiting: Vec<Arc<MemoryRecord>>,
+    // rank of each download in the order set by Archive::prioritize
+    priority: HashMap<MediaStreamId, usize>,
+    // number of download tasks currently running
+    running: usize,
+}
+
+impl Locked {
+    fn remove(&mut self, id: MediaStreamId) -> Option<Arc<MemoryRecord>> {
+        // urls may since have been taken over by a newer download
+        self.media_by_url.retain(|_, url_id| *url_id != id);
+        self.media.remove(&id)
+    }
+
+    fn next_waiting(&mut self) -> Option<Arc<MemoryRecord>> {
+        let (index, _) = self.waiting.iter()
+            .enumerate()
+            .min_by_key(|(index, record)| {
+                let rank = self.priority.get(&record.id).copied().unwrap_or(usize::MAX);
+                (rank, *index)
+            })?;
+
+        Some(self.waiting.remove(index))
+    }
 }
 
-#[derive(Clone)]
 pub struct MemoryRecord {
     pub id: MediaStreamId,
     pub url: Url,
-    pub download: Arc<ytdlp::DownloadHandle>,
+    created_at: DateTime<Utc>,
+    source: DownloadSource,
+    clip: Clip,
+    processing: Processing,
+    // timestamps in the metadata, like chapters, no longer line up with
+    // media which has had parts cut out of it
+    cuts_media: bool,
+    // what we knew about the media when it was added, until yt-dlp
+    // starts downloading and writes out the full metadata
+    preview: Metadata,
+    cancel: CancellationToken,
+    download: watch::Sender<DownloadSlot>,
+}
+
+#[derive(Clone)]
+enum DownloadSlot {
+    Waiting,
+    Started(Arc<ytdlp::DownloadHandle>),
+    // waiting to try again after the given error
+    Retrying(DownloadError),
+    Failed(DownloadError),
 }
 
 impl MemoryRecord {
-    pub fn metadata(&self) -> &ytdlp::Metadata {
-        &self.download.metadata
+    pub fn metadata(&self) -> Metadata {
+        let mut metadata = match self.download() {
+            Some(download) => download.metadata.clone(),
+            None => self.preview.clone(),
+        };
+
+        if self.cuts_media {
+            metadata.chapters = None;
+        }
+
+        metadata
+    }
+
+    /// Returns None if the download is waiting its turn or failed to start
+    pub fn download(&self) -> Option<Arc<ytdlp::DownloadHandle>> {
+        match &*self.download.borrow() {
+            DownloadSlot::Started(download) => Some(download.clone()),
+            DownloadSlot::Waiting | DownloadSlot::Retrying(_) | DownloadSlot::Failed(_) => None,
+        }
+    }
+
+    /// Waits for the download to get its turn and start
+    pub async fn started(&self) -> Result<Arc<ytdlp::DownloadHandle>, DownloadError> {
+        let mut slot = self.download.subscribe();
+
+        loop {
+            let current = slot.borrow_and_update().clone();
+
+            match current {
+                DownloadSlot::Started(download) => return Ok(download),
+                DownloadSlot::Failed(e) => return Err(e),
+                DownloadSlot::Waiting | DownloadSlot::Retrying(_) => {}
+            }
+
+            // the sender is owned by self, so this can't fail while we're borrowing it
+            let _ = slot.changed().await;
+        }
+    }
+
+    pub fn download_status(&self) -> DownloadStatus {
+        let download = match &*self.download.borrow() {
+            DownloadSlot::Started(download) => download.clone(),
+            DownloadSlot::Waiting => return DownloadStatus {
+                state: DownloadState::Waiting,
+                downloaded_bytes: 0,
+                total_bytes: None,
+                error: None,
+            },
+            DownloadSlot::Retrying(e) => return DownloadStatus {
+                state: DownloadState::Retrying,
+                downloaded_bytes: 0,
+                total_bytes: None,
+                error: Some(e.to_string()),
+            },
+            DownloadSlot::Failed(e) => return DownloadStatus {
+                state: DownloadState::Failed,
+                downloaded_bytes: 0,
+                total_bytes: None,
+                error: Some(e.to_string()),
+            },
+        };
+
+        let progress = download.progress.borrow().clone();
+
+        let (state, error) = match download.complete.peek() {
+            None => (DownloadState::Downloading, None),
+            Some(Ok(Ok(()))) => (DownloadState::Complete, None),
+            Some(Ok(Err(e))) => (DownloadState::Failed, Some(e.to_string())),
+            Some(Err(_)) => (DownloadState::Failed, Some("download task terminated".to_owned())),
+        };
+
+        DownloadStatus {
+            state,
+            downloaded_bytes: progress.downloaded_bytes,
+            total_bytes: progress.expected_bytes(),
+            error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::iter;
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::db;
+    use crate::ytdlp::YtDlpError;
+
+    // an archive under a fresh temporary directory, which runs `ytdlp` as
+    // a shell script in place of yt-dlp
+    async fn test_archive(ytdlp: &str) -> (PathBuf, Archive) {
+        let root = std::env::temp_dir().join(format!("hailsplay-test-{}", Uuid::new_v4()));
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("yt-dlp.sh"), ytdlp).unwrap();
+
+        let config = toml::from_str::<Config>(&format!(r#"
+            [http]
+            listen = "127.0.0.1:0"
+            internal_url = "http://127.0.0.1/"
+            external_url = "http://127.0.0.1/"
+
+            [mpd]
+            socket = "/nonexistent"
+
+            [storage]
+            archive = "{root}/archive"
+            working = "{root}/working"
+            database = ":memory:"
+
+            [ytdlp]
+            binary = "/bin/sh"
+            extra_args = ["{root}/yt-dlp.sh"]
+            concurrent_downloads = 1
+
+            [postprocess]
+            normalize_loudness = false
+        "#, root = root.display())).unwrap();
+
+        let working = WorkingDirectory::open_or_create(&config.storage.working).await.unwrap();
+        let archive = Archive::new(db::open_in_memory(), working, reqwest::Client::new(), &config);
+
+        (root, archive)
+    }
+
+    fn job(url: &str) -> DownloadJob {
+        DownloadJob {
+            stream_uuid: MediaStreamId(Uuid::new_v4()),
+            url: url.parse().unwrap(),
+            created_at: Utc::now(),
+            metadata: serde_json::json!({}),
+            error: None,
+            source: DownloadSource::YtDlp,
+            clip: Clip::default(),
+            processing: Processing::default(),
+        }
+    }
+
+    // a yt-dlp download of `id` which has written its files and resolves
+    // `complete` once it's done
+    async fn download(
+        archive: &Archive,
+        id: MediaStreamId,
+        complete: future::Shared<oneshot::Receiver<Result<(), DownloadError>>>,
+    ) -> Arc<ytdlp::DownloadHandle> {
+        let dir = archive.shared.working.create_dir(format!("{id}.1")).await.unwrap().into_shared();
+        tokio::fs::write(dir.path().join("a.opus"), "media").await.unwrap();
+        tokio::fs::write(dir.path().join("a.info.json"), "{}").await.unwrap();
+
+        let progress = Progress { downloaded_bytes: 5, total_bytes: Some(5), total_bytes_estimate: None };
+
+        Arc::new(ytdlp::DownloadHandle {
+            file: watch::channel(dir.claim_external_file(Path::new("a.opus")).into_shared()).1,
+            thumbnail: None,
+            subtitles: None,
+            other_subtitles: Vec::new(),
+            metadata: Metadata::default(),
+            metadata_file: dir.claim_external_file(Path::new("a.info.json")).into_shared(),
+            progress: watch::channel(progress).1,
+            complete,
+            cancel: CancellationToken::new(),
+            dir,
+        })
+    }
+
+    fn attempts(root: &Path) -> usize {
+        std::fs::read_to_string(root.join("attempts")).unwrap_or_default().lines().count()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cancel_waiting() {
+        let (root, archive) = test_archive("exit 1").await;
+        let record = archive.insert_record(job("https://example.com/a"), Metadata::default(), DownloadSlot::Waiting);
+
+        assert!(archive.cancel(record.id).await.unwrap());
+        assert!(archive.active_downloads().is_empty());
+        assert!(archive.shared.locked.lock().unwrap().waiting.is_empty());
+        assert!(matches!(record.started().await, Err(DownloadError::Cancelled)));
+
+        // there's nothing left to cancel the second time round
+        assert!(!archive.cancel(record.id).await.unwrap());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cancel_refuses_finished_download() {
+        let (root, archive) = test_archive("exit 1").await;
+        let job = job("https://example.com/a");
+
+        let complete = ytdlp::spawn_download(future::ready(Ok(())));
+        complete.clone().await.unwrap().unwrap();
+
+        let download = download(&archive, job.stream_uuid, complete).await;
+        let record = archive.insert_record(job, Metadata::default(), DownloadSlot::Started(download));
+
+        assert!(!archive.cancel(record.id).await.unwrap());
+        assert_eq!(archive.active_downloads(), [record.id]);
+        assert!(!record.cancel.is_cancelled());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cancel_just_before_download_finishes() {
+        let (root, archive) = test_archive("exit 1").await;
+        let job = job("https://example.com/a");
+
+        // stands in for a yt-dlp which exits successfully before noticing
+        // it has been told to stop
+        let (finish, finished) = oneshot::channel();
+        let complete = ytdlp::spawn_download(async move { finished.await.unwrap() });
+
+        let download = download(&archive, job.stream_uuid, complete).await;
+        let record = archive.insert_record(job, Metadata::default(), DownloadSlot::Started(download.clone()));
+        let archiving = tokio::spawn(archive_once_download_complete(archive.shared.clone(), record.clone(), download));
+
+        assert!(archive.cancel(record.id).await.unwrap());
+        finish.send(Ok(())).unwrap();
+
+        let result = archiving.await.unwrap();
+        assert!(matches!(result, Err(ArchiveError::DownloadFailed(DownloadError::Cancelled))), "{result:?}");
+        assert!(!archive.shared.archive_dir.join(record.id.to_string()).exists());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn waiting_downloads_start_by_priority_then_age() {
+        let (root, archive) = test_archive("exit 1").await;
+
+        let [a, b, c, d] = ["a", "b", "c", "d"].map(|name| {
+            let job = job(&format!("https://example.com/{name}"));
+            archive.insert_record(job, Metadata::default(), DownloadSlot::Waiting).id
+        });
+
+        archive.prioritize([c, a]);
+
+        let mut locked = archive.shared.locked.lock().unwrap();
+        let order = iter::from_fn(|| locked.next_waiting()).map(|record| record.id).collect::<Vec<_>>();
+        assert_eq!(order, [c, a, b, d]);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn schedule_keeps_to_concurrency_limit() {
+        let (root, archive) = test_archive("exec sleep 60").await;
+
+        let [a, b, c] = ["a", "b", "c"].map(|name| {
+            let job = job(&format!("https://example.com/{name}"));
+            archive.insert_record(job, Metadata::default(), DownloadSlot::Waiting).id
+        });
+
+        let waiting = || -> Vec<_> {
+            archive.shared.locked.lock().unwrap().waiting.iter().map(|record| record.id).collect()
+        };
+
+        schedule(&archive.shared);
+        assert_eq!(waiting(), [b, c]);
+
+        // the next download takes over once one is cancelled
+        let mut changed = archive.watch_downloads();
+        assert!(archive.cancel(a).await.unwrap());
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while waiting() != [c] {
+                changed.changed().await.unwrap();
+            }
+        }).await.unwrap();
+
+        assert_eq!(archive.shared.locked.lock().unwrap().running, 1);
+
+        // let the download still starting wind down before cleaning up
+        // under it
+        for id in archive.active_downloads() {
+            archive.cancel(id).await.unwrap();
+        }
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while archive.shared.locked.lock().unwrap().running > 0 {
+                changed.changed().await.unwrap();
+            }
+        }).await.unwrap();
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resume_restarts_unfinished_jobs() {
+        let (root, archive) = test_archive("exec sleep 60").await;
+
+        let unfinished = job("https://example.com/a");
+        let failed = DownloadJob { error: Some("media is private".to_owned()), ..job("https://example.com/b") };
+        let (unfinished_id, failed_id) = (unfinished.stream_uuid, failed.stream_uuid);
+
+        archive.shared.database.with(|conn| {
+            download::insert_job(conn, &unfinished)?;
+            download::insert_job(conn, &failed)
+        }).await.unwrap();
+
+        // the previous run left a partial download behind
+        let working = archive.shared.working.path();
+        let leftover = working.join(format!("{}.2", Uuid::new_v4()));
+        std::fs::create_dir(&leftover).unwrap();
+        std::fs::write(leftover.join("a.webm"), "partial").unwrap();
+        std::fs::write(working.join("notes.txt"), "not ours").unwrap();
+
+        archive.resume().await.unwrap();
+
+        assert!(!leftover.exists());
+        assert!(working.join("notes.txt").exists());
+
+        let active = archive.active_downloads().into_iter().collect::<HashSet<_>>();
+        assert_eq!(active, HashSet::from([unfinished_id, failed_id]));
+
+        let status = archive.download_status(failed_id).unwrap();
+        assert_eq!(status.state, DownloadState::Failed);
+        assert_eq!(status.error.as_deref(), Some("media is private"));
+
+        // only the unfinished job was started again
+        assert_eq!(archive.shared.locked.lock().unwrap().running, 1);
+
+        // let the download still starting wind down before cleaning up
+        // under it
+        let mut changed = archive.watch_downloads();
+
+        for id in archive.active_downloads() {
+            archive.cancel(id).await.unwrap();
+        }
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while archive.shared.locked.lock().unwrap().running > 0 {
+                changed.changed().await.unwrap();
+            }
+        }).await.unwrap();
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transient_failures_are_retried_with_backoff() {
+        let (root, archive) = test_archive(r#"
+            echo >> "$(dirname "$0")/attempts"
+            echo "ERROR: unable to download video data: HTTP Error 503: Service Unavailable" >&2
+            exit 1
+        "#).await;
+
+        archive.insert_record(job("https://example.com/a"), Metadata::default(), DownloadSlot::Waiting);
+        let record = archive.shared.locked.lock().unwrap().next_waiting().unwrap();
+
+        let started = tokio::time::Instant::now();
+        let result = download_with_retries(&archive.shared, &record).await;
+
+        assert!(matches!(result,
+            Err(ArchiveError::DownloadFailed(DownloadError::CommandError(YtDlpError::Network(_))))), "{result:?}");
+        assert_eq!(attempts(&root), MAX_ATTEMPTS as usize);
+        assert_eq!(record.download_status().state, DownloadState::Failed);
+
+        // waiting 5s, 10s and then 20s between attempts
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_secs(35) && waited < Duration::from_secs(36), "{waited:?}");
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn permanent_failures_are_not_retried() {
+        let (root, archive) = test_archive(r#"
+            echo >> "$(dirname "$0")/attempts"
+            echo "ERROR: [youtube] abc: Private video. Sign in if you've been granted access to this video" >&2
+            exit 1
+        "#).await;
+
+        archive.insert_record(job("https://example.com/a"), Metadata::default(), DownloadSlot::Waiting);
+        let record = archive.shared.locked.lock().unwrap().next_waiting().unwrap();
+
+        let result = download_with_retries(&archive.shared, &record).await;
+
+        assert!(matches!(result,
+            Err(ArchiveError::DownloadFailed(DownloadError::CommandError(YtDlpError::Private)))), "{result:?}");
+        assert_eq!(attempts(&root), 1);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_while_waiting_to_retry() {
+        let (root, archive) = test_archive(r#"
+            echo >> "$(dirname "$0")/attempts"
+            echo "ERROR: unable to download video data: <urlopen error timed out>" >&2
+            exit 1
+        "#).await;
+
+        archive.insert_record(job("https://example.com/a"), Metadata::default(), DownloadSlot::Waiting);
+        let record = archive.shared.locked.lock().unwrap().next_waiting().unwrap();
+
+        let mut slot = record.download.subscribe();
+
+        let cancel = async {
+            slot.wait_for(|slot| matches!(slot, DownloadSlot::Retrying(_))).await.unwrap();
+            assert!(archive.cancel_record(record.id));
+        };
+
+        let started = tokio::time::Instant::now();
+        let (result, ()) = future::join(download_with_retries(&archive.shared, &record), cancel).await;
+
+        assert!(matches!(result, Err(ArchiveError::DownloadFailed(DownloadError::Cancelled))), "{result:?}");
+        assert_eq!(attempts(&root), 1);
+        assert!(started.elapsed() < RETRY_BACKOFF);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn promote_links_download_into_archive() {
+        let (root, archive) = test_archive("exit 1").await;
+        let id = MediaStreamId(Uuid::new_v4());
+
+        let complete = ytdlp::spawn_download(future::ready(Ok(())));
+        let download = download(&archive, id, complete).await;
+
+        let path = promote(&archive.shared.archive_dir, id, &download).await.unwrap();
+        assert_eq!(path, format!("{id}/a.opus"));
+
+        let archived = archive.shared.archive_dir.join(&path);
+        assert_eq!(std::fs::read_to_string(archived).unwrap(), "media");
+        assert!(archive.shared.archive_dir.join(id.to_string()).join("a.info.json").exists());
+
+        // the download can still be served from where it was
+        assert_eq!(std::fs::read_to_string(download.file().path()).unwrap(), "media");
+
+        drop(download);
+        std::fs::remove_dir_all(root).unwrap();
     }
 }
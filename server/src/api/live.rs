@@ -0,0 +1,154 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::Utc;
+use thiserror::Error;
+use tokio::time::Instant;
+use url::Url;
+
+use crate::config::{self, Config};
+use crate::db::Pool;
+use crate::db::live::{self, LiveStream};
+use crate::api::archive::MediaStreamId;
+use crate::ytdlp::{self, Metadata, ResolveUrlError};
+
+// how long to trust a resolved url which doesn't say when it expires
+const DEFAULT_URL_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+// resolve again a little before the site says the url expires, so the
+// player isn't handed a url that dies as it connects
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+// live streams are added shortly before their queue item, so they're only
+// removed for missing from the queue once they've had this long to get in
+const QUEUE_GRACE: Duration = Duration::from_secs(60);
+
+/// Live streams in the queue. These never finish downloading, so rather
+/// than archiving them the player streams them directly, like a radio
+/// station, through a url of ours which redirects to the source
+#[derive(Clone)]
+pub struct LiveStreams {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    database: Pool,
+    ytdlp: config::YtDlp,
+    resolved: Mutex<HashMap<MediaStreamId, Resolved>>,
+}
+
+#[derive(Clone)]
+struct Resolved {
+    url: Url,
+    expires_at: Instant,
+}
+
+#[derive(Debug, Error)]
+pub enum StreamUrlError {
+    #[error("database error: {0}")]
+    Database(#[from] rusqlite::Error),
+    #[error("resolving stream url: {0}")]
+    Resolve(#[from] ResolveUrlError),
+}
+
+impl LiveStreams {
+    pub fn new(database: Pool, ytdlp: config::YtDlp) -> Self {
+        let shared = Shared {
+            database,
+            ytdlp,
+            resolved: Mutex::default(),
+        };
+
+        LiveStreams { shared: Arc::new(shared) }
+    }
+
+    pub async fn add(&self, url: &Url, metadata: &Metadata) -> anyhow::Result<MediaStreamId> {
+        let stream = LiveStream {
+            stream_uuid: MediaStreamId(uuid::Uuid::new_v4()),
+            url: url.clone(),
+            created_at: Utc::now(),
+            metadata: serde_json::to_value(metadata)?,
+        };
+
+        self.shared.database.with(|conn| live::insert(conn, &stream)).await?;
+
+        Ok(stream.stream_uuid)
+    }
+
+    pub async fn load(&self, id: MediaStreamId) -> Result<Option<LiveStream>, rusqlite::Error> {
+        self.shared.database.with(|conn| live::load(conn, &id)).await
+    }
+
+    /// Removes live streams which are no longer in the queue, given the ids
+    /// of those that are
+    pub async fn remove_unqueued(&self, in_queue: &HashSet<MediaStreamId>) -> Result<(), rusqlite::Error> {
+        let added_before = Utc::now() - chrono::Duration::from_std(QUEUE_GRACE).unwrap();
+
+        let removed = self.shared.database.with(|conn| {
+            let removed = live::created_before(conn, added_before)?
+                .into_iter()
+                .filter(|id| !in_queue.contains(id))
+                .collect::<Vec<_>>();
+
+            for id in &removed {
+                live::delete(conn, id)?;
+            }
+
+            Ok::<_, rusqlite::Error>(removed)
+        }).await?;
+
+        let mut resolved = self.shared.resolved.lock().unwrap();
+
+        for id in removed {
+            log::info!("removing live stream no longer in the queue: {id}");
+            resolved.remove(&id);
+        }
+
+        Ok(())
+    }
+
+    /// Returns a direct url for the live stream, resolving it again if the
+    /// last one has expired. None if there is no such live stream
+    pub async fn stream_url(&self, id: MediaStreamId) -> Result<Option<Url>, StreamUrlError> {
+        if let Some(resolved) = self.shared.resolved.lock().unwrap().get(&id) {
+            if resolved.expires_at > Instant::now() {
+                return Ok(Some(resolved.url.clone()));
+            }
+        }
+
+        let Some(stream) = self.load(id).await? else {
+            return Ok(None);
+        };
+
+        let url = ytdlp::resolve_stream_url(&self.shared.ytdlp, &stream.url).await?;
+        log::info!("resolved live stream {} to {}", stream.url, url.host_str().unwrap_or_default());
+
+        let resolved = Resolved { expires_at: expires_at(&url), url: url.clone() };
+
+        let mut memory = self.shared.resolved.lock().unwrap();
+        memory.retain(|_, resolved| resolved.expires_at > Instant::now());
+        memory.insert(id, resolved);
+
+        Ok(Some(url))
+    }
+}
+
+pub fn internal_stream_url(config: &Config, id: MediaStreamId) -> Url {
+    let path = format!("live/{id}/stream");
+    config.http.internal_url.join(&path).unwrap()
+}
+
+// signed stream urls (eg. youtube's) carry their expiry as a unix
+// timestamp in the query string
+fn expires_at(url: &Url) -> Instant {
+    let lifetime = url.query_pairs()
+        .find(|(key, _)| key == "expire")
+        .and_then(|(_, value)| value.parse::<u64>().ok())
+        .map(|expire| UNIX_EPOCH + Duration::from_secs(expire))
+        .and_then(|expire| expire.duration_since(SystemTime::now()).ok())
+        .map(|remaining| remaining.saturating_sub(EXPIRY_MARGIN))
+        .unwrap_or(DEFAULT_URL_LIFETIME);
+
+    Instant::now() + lifetime
+}
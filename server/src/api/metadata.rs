@@ -3,25 +3,33 @@ use std::str::FromStr;
 use regex::Regex;
 use url::Url;
 
-use hailsplay_protocol::TrackInfo;
+use hailsplay_protocol::{Chapter, TrackInfo};
 
 use crate::api::archive::MediaStreamId;
+use crate::clip::Clip;
+use crate::db::live::LiveStream;
 use crate::db::radio::{self, Station};
 use crate::http::assets;
-use crate::mpd::PlaylistItem;
+use crate::mpd::{PlaylistItem, Range};
+use crate::ytdlp::Metadata;
 use crate::api::Session;
 
 pub async fn track_info(session: &mut Session, item: &TrackKind) -> anyhow::Result<TrackInfo> {
     match item {
-        TrackKind::Media(id) => Ok(media_track_info(session, *id).await?),
+        TrackKind::Media(id, range) => Ok(media_track_info(session, *id, *range).await?),
         TrackKind::Radio(item) => Ok(radio_track_info(session, item).await?),
+        TrackKind::Live(stream) => Ok(live_track_info(stream)?),
         TrackKind::Unknown(item) => Ok(fallback_item(item)),
     }
 }
 
 pub async fn identify(session: &mut Session, item: &PlaylistItem) -> anyhow::Result<TrackKind> {
-    if let Some(id) = media_stream_item(session, &item).await? {
-        return Ok(TrackKind::Media(id));
+    if let Some(id) = media_stream_item(session, item).await? {
+        return Ok(TrackKind::Media(id, item.range));
+    }
+
+    if let Some(stream) = live_stream_item(session, item).await? {
+        return Ok(TrackKind::Live(stream));
     }
 
     if let Some(station) = radio_item(session, &item).await? {
@@ -33,7 +41,10 @@ pub async fn identify(session: &mut Session, item: &PlaylistItem) -> anyhow::Res
 
 pub enum TrackKind {
     Radio(RadioItem),
-    Media(MediaStreamId),
+    // with the range of the media the item plays, if it's been split
+    // into chapters
+    Media(MediaStreamId, Option<Range>),
+    Live(LiveStream),
     Unknown(PlaylistItem),
 }
 
@@ -59,6 +70,8 @@ fn fallback_item(item: &PlaylistItem) -> TrackInfo {
         image_url: None,
         primary_label,
         secondary_label: None,
+        live: false,
+        chapters: Vec::new(),
     }
 }
 
@@ -72,6 +85,8 @@ async fn radio_track_info(session: &Session, item: &RadioItem) -> Result<TrackIn
             image_url: Some(image_url),
             primary_label,
             secondary_label,
+            live: false,
+            chapters: Vec::new(),
         })
     }).await
 }
@@ -85,7 +100,7 @@ async fn radio_item(session: &Session, item: &PlaylistItem) -> Result<Option<Rad
     }).await
 }
 
-async fn media_track_info(session: &Session, id: MediaStreamId) -> anyhow::Result<TrackInfo> {
+async fn media_track_info(session: &Session, id: MediaStreamId, range: Option<Range>) -> anyhow::Result<TrackInfo> {
     let media_record = session.app()
         .archive()
         .load(id)
@@ -104,33 +119,113 @@ async fn media_track_info(session: &Session, id: MediaStreamId) -> anyhow::Resul
 
     let secondary_label = metadata.uploader.clone();
 
+    let chapters = chapters(&metadata, media_record.clip());
+
+    // items split into chapters go by the chapter they play
+    let chapter = range.and_then(|range| {
+        chapters.iter().find(|chapter| (chapter.start - range.start.0).abs() < 0.5)
+    });
+
+    if let Some(chapter) = chapter {
+        return Ok(TrackInfo {
+            image_url,
+            primary_label: chapter.title.clone(),
+            secondary_label: Some(primary_label),
+            live: false,
+            chapters: Vec::new(),
+        });
+    }
+
     Ok(TrackInfo {
         image_url,
         primary_label,
         secondary_label,
+        live: false,
+        chapters,
     })
 }
 
+/// Chapters of the media, in the time of the clip that was downloaded
+pub fn chapters(metadata: &Metadata, clip: Clip) -> Vec<Chapter> {
+    let Some(chapters) = &metadata.chapters else {
+        return Vec::new();
+    };
+
+    chapters.iter()
+        .enumerate()
+        .filter_map(|(index, chapter)| {
+            let (start, end) = clip.span_in_clip(chapter.start_time, chapter.end_time)?;
+
+            let title = chapter.title.clone()
+                .unwrap_or_else(|| format!("Chapter {}", index + 1));
+
+            Some(Chapter { title, start, end })
+        })
+        .collect()
+}
+
+fn live_track_info(stream: &LiveStream) -> Result<TrackInfo, serde_json::Error> {
+    let metadata = stream.parse_metadata()?;
+
+    let primary_label = metadata.title
+        .unwrap_or_else(|| stream.url.to_string());
+
+    Ok(TrackInfo {
+        image_url: metadata.thumbnail,
+        primary_label,
+        secondary_label: metadata.uploader,
+        live: true,
+        chapters: Vec::new(),
+    })
+}
+
+async fn live_stream_item(session: &Session, item: &PlaylistItem)
+    -> Result<Option<LiveStream>, rusqlite::Error>
+{
+    let Some(id) = parse_live_url(&item.file) else {
+        return Ok(None);
+    };
+
+    session.app().live_streams().load(id).await
+}
+
 async fn media_stream_item(session: &Session, item: &PlaylistItem)
     -> Result<Option<MediaStreamId>, rusqlite::Error>
 {
+    let Some(id) = parse_stream_url(&item.file) else {
+        return Ok(None);
+    };
+
+    // validate parsed id by trying to load it and seeing if it exists:
+    let record = session.app().archive().load(id).await?;
+    Ok(record.map(|_| id))
+}
+
+/// Extracts the media stream id from a playlist item's file url, without
+/// checking that the media exists
+pub fn parse_stream_url(file: &str) -> Option<MediaStreamId> {
     lazy_static::lazy_static! {
         static ref URL_RE: Regex =
             Regex::new("^/media/(.*?)/stream$").unwrap();
     }
 
-    let parsed = Url::parse(&item.file).ok();
+    parse_id(&URL_RE, file)
+}
 
-    let id = parsed.as_ref()
-        .and_then(|url| URL_RE.captures(url.path()))
-        .and_then(|captures| captures.get(1))
-        .and_then(|capture| MediaStreamId::from_str(capture.as_str()).ok());
+pub fn parse_live_url(file: &str) -> Option<MediaStreamId> {
+    lazy_static::lazy_static! {
+        static ref URL_RE: Regex =
+            Regex::new("^/live/(.*?)/stream$").unwrap();
+    }
 
-    let Some(id) = id else {
-        return Ok(None);
-    };
+    parse_id(&URL_RE, file)
+}
 
-    // validate parsed id by trying to load it and seeing if it exists:
-    let record = session.app().archive().load(id).await?;
-    Ok(record.map(|_| id))
+fn parse_id(path_re: &Regex, file: &str) -> Option<MediaStreamId> {
+    let parsed = Url::parse(file).ok();
+
+    parsed.as_ref()
+        .and_then(|url| path_re.captures(url.path()))
+        .and_then(|captures| captures.get(1))
+        .and_then(|capture| MediaStreamId::from_str(capture.as_str()).ok())
 }
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::OptionalExtension;
+use url::Url;
+
+use crate::clip::Clip;
+use crate::config;
+use crate::db::{self, Pool};
+use crate::ytdlp::{self, FetchMetadataError, Info};
+
+/// Remembers yt-dlp metadata lookups, in memory and in the database, so
+/// that looking up the same url repeatedly only runs yt-dlp once
+#[derive(Clone)]
+pub struct MetadataCache {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    database: Pool,
+    ytdlp: config::YtDlp,
+    ttl: Duration,
+    memory: Mutex<HashMap<String, CacheEntry>>,
+}
+
+#[derive(Clone)]
+struct CacheEntry {
+    fetched_at: DateTime<Utc>,
+    info: Info,
+}
+
+impl MetadataCache {
+    pub fn new(database: Pool, ytdlp: config::YtDlp) -> Self {
+        // clamped so that subtracting it from the current time can't overflow
+        let ttl = Duration::seconds(i64::from(u32::try_from(ytdlp.metadata_cache_ttl).unwrap_or(u32::MAX)));
+
+        let shared = Shared {
+            database,
+            ytdlp,
+            ttl,
+            memory: Mutex::default(),
+        };
+
+        MetadataCache { shared: Arc::new(shared) }
+    }
+
+    pub async fn fetch(&self, url: &Url) -> Result<Info, FetchMetadataError> {
+        if let Some(info) = self.cached(url).await {
+            return Ok(info);
+        }
+
+        let key = normalize_url(url);
+        let fresh_after = Utc::now() - self.shared.ttl;
+
+        let info = ytdlp::fetch_metadata(&self.shared.ytdlp, url).await?;
+        let entry = CacheEntry { fetched_at: Utc::now(), info: info.clone() };
+
+        if let Err(e) = self.store(&key, &entry, fresh_after).await {
+            log::warn!("caching metadata for {url}: {e:?}");
+        }
+
+        self.remember(key, entry, fresh_after);
+
+        Ok(info)
+    }
+
+    /// Answers from the cache alone, without asking yt-dlp
+    pub async fn cached(&self, url: &Url) -> Option<Info> {
+        let key = normalize_url(url);
+        let fresh_after = Utc::now() - self.shared.ttl;
+
+        if let Some(entry) = self.shared.memory.lock().unwrap().get(&key) {
+            if entry.fetched_at > fresh_after {
+                return Some(entry.info.clone());
+            }
+        }
+
+        // cache failures shouldn't stop the lookup, we can always ask yt-dlp
+        let cached = self.load(url, &key, fresh_after).await.unwrap_or_else(|e| {
+            log::warn!("loading cached metadata for {url}: {e:?}");
+            None
+        });
+
+        let entry = cached?;
+        self.remember(key, entry.clone(), fresh_after);
+        Some(entry.info)
+    }
+
+    async fn load(&self, url: &Url, key: &str, fresh_after: DateTime<Utc>) -> anyhow::Result<Option<CacheEntry>> {
+        self.shared.database.with(|conn| {
+            // archived media doesn't go stale, answer from its metadata
+            let archived = db::archive::load_by_canonical_url(conn, url, Clip::default()).optional()?;
+
+            if let Some((_, record)) = archived {
+                return Ok(Some(CacheEntry {
+                    fetched_at: Utc::now(),
+                    info: Info::Single(Box::new(record.parse_metadata()?)),
+                }));
+            }
+
+            let cached = db::metadata_cache::load(conn, key, fresh_after)?;
+
+            match cached {
+                Some((fetched_at, info)) => Ok(Some(CacheEntry {
+                    fetched_at,
+                    info: serde_json::from_value(info)?,
+                })),
+                None => Ok(None),
+            }
+        }).await
+    }
+
+    async fn store(&self, key: &str, entry: &CacheEntry, fresh_after: DateTime<Utc>) -> anyhow::Result<()> {
+        let info = serde_json::to_value(&entry.info)?;
+
+        self.shared.database.with(|conn| {
+            db::metadata_cache::delete_stale(conn, fresh_after)?;
+            db::metadata_cache::store(conn, key, entry.fetched_at, &info)
+        }).await?;
+
+        Ok(())
+    }
+
+    fn remember(&self, key: String, entry: CacheEntry, fresh_after: DateTime<Utc>) {
+        let mut memory = self.shared.memory.lock().unwrap();
+        memory.retain(|_, entry| entry.fetched_at > fresh_after);
+        memory.insert(key, entry);
+    }
+}
+
+/// Normalizes `url` for use as a cache key, so that trivially different
+/// urls for the same media share an entry
+pub fn normalize_url(url: &Url) -> String {
+    const TRACKING_PARAMS: &[&str] = &["feature", "si", "fbclid", "gclid"];
+
+    let mut url = url.clone();
+    url.set_fragment(None);
+
+    if let Some(host) = url.host_str().and_then(|host| host.strip_prefix("www.")) {
+        let host = host.to_owned();
+        // only fails for hosts which can't be set, and we're only
+        // removing a label from an existing domain name
+        let _ = url.set_host(Some(&host));
+    }
+
+    let mut query = url.query_pairs()
+        .filter(|(name, _)| !name.starts_with("utm_") && !TRACKING_PARAMS.contains(&name.as_ref()))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+
+    query.sort();
+
+    if query.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut()
+            .clear()
+            .extend_pairs(query);
+    }
+
+    url.to_string()
+}
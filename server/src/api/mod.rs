@@ -1,15 +1,36 @@
 pub mod archive;
 pub mod asset;
+pub mod live;
 pub mod metadata;
+pub mod metadata_cache;
 pub mod session;
 
 pub use session::Session;
 
-use hailsplay_protocol::{TrackId, PlayPosition, PlayState, PlayerStatus, Queue, QueueItem};
-use crate::mpd::{self, Seconds, Status};
+use futures::{future, stream, StreamExt};
+use hailsplay_protocol::{TrackId, Lyrics, PlayPosition, PlayState, PlayerStatus, Queue, QueueItem, SearchSite};
+use thiserror::Error;
+use url::Url;
+
+use crate::clip::Clip;
+use crate::db::download::DownloadSource;
+use crate::direct::{self, Probe};
+use crate::lyrics;
+use crate::mpd::{self, Mpd, Seconds, Status};
+use crate::postprocess::Processing;
+use crate::ytdlp::{self, Info, Metadata};
 
 use self::metadata::TrackKind;
 
+// urls in an M3U playlist probed at once
+const MAX_CONCURRENT_PROBES: usize = 8;
+
+#[derive(Error, Debug)]
+#[error("no results for {query:?}")]
+pub struct NoSearchResults {
+    pub query: String,
+}
+
 pub async fn status(session: &mut Session) -> anyhow::Result<PlayerStatus> {
     let status = session.mpd().status().await?;
 
@@ -19,6 +40,7 @@ pub async fn status(session: &mut Session) -> anyhow::Result<PlayerStatus> {
         track,
         state: play_state(&status),
         position: play_position(&status),
+        volume: status.volume.and_then(|volume| u8::try_from(volume).ok()),
     })
 }
 
@@ -33,10 +55,16 @@ pub async fn queue(session: &mut Session) -> anyhow::Result<Queue> {
         let item = metadata::identify(session, &item).await?;
         let track = metadata::track_info(session, &item).await?;
 
+        let download = match item {
+            TrackKind::Media(id, _) => session.app().archive().download_status(id),
+            _ => None,
+        };
+
         items.push(QueueItem {
             id,
             position,
             track,
+            download,
         });
     }
 
@@ -49,6 +77,234 @@ pub async fn track(session: &mut Session, id: &TrackId) -> anyhow::Result<Option
     metadata::identify(session, &item).await.map(Some)
 }
 
+/// Lyrics of the media in the queue as `id`, timed to the queue item.
+/// None if the item has none, or isn't media at all
+pub async fn lyrics(session: &mut Session, id: &TrackId) -> anyhow::Result<Option<Lyrics>> {
+    let Some(TrackKind::Media(media_id, range)) = track(session, id).await? else {
+        return Ok(None);
+    };
+
+    let Some(record) = session.app().archive().load(media_id).await? else {
+        return Ok(None);
+    };
+
+    let Some(lines) = record.lyrics(&session.config().storage.archive).await? else {
+        return Ok(None);
+    };
+
+    // items split into chapters play a range of the media, and mpd times
+    // them from the start of the range
+    let lines = match range {
+        Some(range) => lyrics::in_clip(&lines, Clip::from_secs(Some(range.start.0), range.end.map(|end| end.0))),
+        None => lines,
+    };
+
+    Ok(Some(Lyrics { lines }))
+}
+
+/// How media being added is cut up and processed
+#[derive(Debug, Default)]
+pub struct AddOptions {
+    /// Part of the media to play, only for single items. Media plays from
+    /// any timestamp in its url otherwise
+    pub clip: Option<Clip>,
+    /// Give media with chapters a queue item for each
+    pub split_chapters: bool,
+    pub processing: Processing,
+}
+
+/// Adds the media at `url` to the end of the queue. Playlist urls are
+/// expanded into one queue item per entry, optionally limited to the
+/// entries whose indexes are given in `entries`.
+pub async fn add(session: &mut Session, url: &Url, entries: Option<&[u32]>, mut options: AddOptions)
+    -> anyhow::Result<Vec<TrackId>>
+{
+    // an explicit clip is for a single item, playlist entries go by any
+    // timestamps in their own urls
+    let (media, clip) = match probe(session, url).await {
+        Probe::Audio(metadata) => (vec![(url.clone(), *metadata, DownloadSource::Direct)], options.clip),
+        Probe::Playlist(urls) => {
+            log::info!("Expanding M3U playlist {url}: {} entries", urls.len());
+
+            let session = &*session;
+
+            // a few entries at a time, long playlists would take a while
+            // one by one
+            let media = stream::iter(urls.into_iter().enumerate())
+                .filter(|(index, _)| future::ready(selected(*index, entries)))
+                .map(|(_, entry)| async move {
+                    match probe(session, &entry).await {
+                        Probe::Audio(metadata) => (entry, *metadata, DownloadSource::Direct),
+                        // yt-dlp works out what it is once it gets to download it
+                        Probe::Playlist(_) | Probe::Other => (entry, Metadata::default(), DownloadSource::YtDlp),
+                    }
+                })
+                .buffered(MAX_CONCURRENT_PROBES)
+                .collect::<Vec<_>>()
+                .await;
+
+            (media, None)
+        }
+        Probe::Other => match session.app().metadata_cache().fetch(url).await? {
+            Info::Single(metadata) => (vec![(url.clone(), *metadata, DownloadSource::YtDlp)], options.clip),
+            Info::Playlist(playlist) => {
+                log::info!("Expanding playlist {}: {} entries",
+                    playlist.title.as_deref().unwrap_or(url.as_str()),
+                    playlist.entries.len());
+
+                let media = playlist.entries.iter()
+                    .enumerate()
+                    .filter(|(index, _)| selected(*index, entries))
+                    .filter_map(|(_, entry)| Some((entry.url()?, entry.metadata(), DownloadSource::YtDlp)))
+                    .collect();
+
+                (media, None)
+            }
+        },
+    };
+
+    if media.is_empty() {
+        anyhow::bail!("no media to add at {url}");
+    }
+
+    options.clip = clip;
+    enqueue(session, media, options).await
+}
+
+/// Searches `site` for `query` and adds the chosen results, by index, or
+/// the top result if `entries` is None
+pub async fn add_search(
+    session: &mut Session,
+    site: SearchSite,
+    query: &str,
+    entries: Option<&[u32]>,
+    mut options: AddOptions,
+) -> anyhow::Result<Vec<TrackId>> {
+    let no_results = || NoSearchResults { query: query.to_owned() };
+
+    // searches always come back as a playlist, anything else is as good
+    // as finding nothing
+    let Info::Playlist(results) = ytdlp::search(&session.config().ytdlp, site, query).await? else {
+        return Err(no_results().into());
+    };
+
+    let entries = entries.unwrap_or(&[0]);
+
+    let media = results.entries.iter()
+        .enumerate()
+        .filter(|(index, _)| selected(*index, Some(entries)))
+        .filter_map(|(_, entry)| Some((entry.url()?, entry.metadata(), DownloadSource::YtDlp)))
+        .collect::<Vec<_>>();
+
+    if media.is_empty() {
+        return Err(no_results().into());
+    }
+
+    if media.len() > 1 {
+        options.clip = None;
+    }
+
+    enqueue(session, media, options).await
+}
+
+async fn enqueue(session: &mut Session, media: Vec<(Url, Metadata, DownloadSource)>, options: AddOptions)
+    -> anyhow::Result<Vec<TrackId>>
+{
+    let archive = session.app().archive();
+
+    let mut added = Vec::new();
+
+    for (url, metadata, mut source) in media {
+        log::info!("Adding {}", metadata.title.as_deref()
+            .unwrap_or(url.as_str()));
+
+        let clip = options.clip.unwrap_or_else(|| Clip::from_url(&url));
+
+        if !clip.is_full() {
+            // direct downloads fetch the whole file, cutting it is left
+            // to yt-dlp
+            source = DownloadSource::YtDlp;
+        }
+
+        let (stream_url, chapters) = if metadata.is_live == Some(true) {
+            // live streams never finish downloading, so play them
+            // directly like a radio station instead. they don't have
+            // chapters worth splitting either
+            let id = session.app().live_streams().add(&url, &metadata).await?;
+            (live::internal_stream_url(session.config(), id), Vec::new())
+        } else {
+            // the download is started by the archive once it gets its turn
+            let record = archive.add_url(&url, metadata, source, clip, options.processing.clone()).await?;
+
+            // the record leaves out chapters of media which has had parts
+            // cut out of it, as they no longer line up
+            let chapters = match options.split_chapters {
+                true => metadata::chapters(&record.parse_metadata()?, clip),
+                false => Vec::new(),
+            };
+
+            (record.internal_stream_url(session.config()), chapters)
+        };
+
+        if chapters.len() < 2 {
+            added.push(session.mpd().addid(&stream_url).await?);
+            continue;
+        }
+
+        log::info!("Splitting into {} chapters", chapters.len());
+
+        for (index, chapter) in chapters.iter().enumerate() {
+            // the last chapter plays to the end, however long that is
+            let end = (index + 1 < chapters.len()).then_some(Seconds(chapter.end));
+
+            let id = session.mpd().addid(&stream_url).await?;
+            session.mpd().rangeid(&id, Seconds(chapter.start), end).await?;
+            added.push(id);
+        }
+    }
+
+    let Some(first) = added.first() else {
+        anyhow::bail!("failed to add any media");
+    };
+
+    if should_autoplay(session.mpd(), first).await? {
+        session.mpd().play().await?;
+    }
+
+    Ok(added.into_iter().map(TrackId::from).collect())
+}
+
+// probing failures aren't fatal, yt-dlp gets a go at the url instead and
+// reports a more useful error if it's really broken
+async fn probe(session: &Session, url: &Url) -> Probe {
+    // yt-dlp has already had a look at anything in the cache
+    if session.app().metadata_cache().cached(url).await.is_some() {
+        return Probe::Other;
+    }
+
+    direct::probe(&session.app().http(), url).await.unwrap_or_else(|e| {
+        log::debug!("probing {url}: {e}");
+        Probe::Other
+    })
+}
+
+fn selected(index: usize, entries: Option<&[u32]>) -> bool {
+    match entries {
+        Some(entries) => u32::try_from(index).is_ok_and(|index| entries.contains(&index)),
+        None => true,
+    }
+}
+
+async fn should_autoplay(mpd: &mut Mpd, added_id: &mpd::Id) -> anyhow::Result<bool> {
+    let playlist = mpd.playlistinfo().await?;
+
+    // only autoplay if the queue was empty before we added to it
+    match playlist.items.first() {
+        Some(item) => Ok(item.id == *added_id),
+        None => Ok(false),
+    }
+}
+
 fn play_state(status: &Status) -> PlayState {
     if status.state == mpd::PlayerState::Play {
         if status.audio_format.is_none() {
@@ -0,0 +1,384 @@
+use futures::StreamExt;
+use hailsplay_client::Client;
+use hailsplay_protocol::{DownloadState, DownloadStatus, PlayPosition, PlayState, PlayerStatus, Queue, SearchSite, ServerMessage, TrackInfo};
+use serde::Serialize;
+use structopt::StructOpt;
+use url::Url;
+
+use crate::config;
+
+// client subcommands, these talk to a running hailsplay server
+
+#[derive(StructOpt)]
+pub enum Cmd {
+    /// Show the player state and current track
+    Status(Opt),
+    /// Show the lyrics of the current track
+    Lyrics(Opt),
+    /// List the play queue
+    Queue(Opt),
+    /// Add online media to the end of the queue, by url or search query
+    Add(AddOpt),
+    /// List search results, to pick from with `add --entry`
+    Search(SearchOpt),
+    /// Resume playback
+    Play(Opt),
+    /// Pause playback
+    Pause(Opt),
+    /// Skip to the next track in the queue
+    Next(Opt),
+    /// Tune to a radio station by name or stream url
+    Tune(TuneOpt),
+    /// Print events from the server as they happen
+    Watch(Opt),
+}
+
+#[derive(StructOpt)]
+pub struct Opt {
+    /// Base url of the hailsplay server, defaults to http.internal_url from config.toml
+    #[structopt(long, env = "HAILSPLAY_URL")]
+    server: Option<Url>,
+    /// Print machine readable JSON instead of human readable output
+    #[structopt(long)]
+    json: bool,
+}
+
+#[derive(StructOpt)]
+pub struct AddOpt {
+    #[structopt(flatten)]
+    opt: Opt,
+    /// Url of the media, anything else is searched for
+    target: String,
+    /// Only add these playlist entries or search results, by index
+    #[structopt(long = "entry")]
+    entries: Vec<u32>,
+    /// Search SoundCloud rather than YouTube
+    #[structopt(long)]
+    soundcloud: bool,
+}
+
+#[derive(StructOpt)]
+pub struct SearchOpt {
+    #[structopt(flatten)]
+    opt: Opt,
+    query: String,
+    /// Search SoundCloud rather than YouTube
+    #[structopt(long)]
+    soundcloud: bool,
+}
+
+#[derive(StructOpt)]
+pub struct TuneOpt {
+    #[structopt(flatten)]
+    opt: Opt,
+    station: String,
+}
+
+pub async fn run(cmd: Cmd) -> anyhow::Result<()> {
+    match cmd {
+        Cmd::Status(opt) => status(&opt).await,
+        Cmd::Lyrics(opt) => lyrics(&opt).await,
+        Cmd::Queue(opt) => queue(&opt).await,
+        Cmd::Add(opt) => add(&opt).await,
+        Cmd::Search(opt) => search(&opt).await,
+        Cmd::Play(opt) => Ok(client(&opt)?.play().await?),
+        Cmd::Pause(opt) => Ok(client(&opt)?.pause().await?),
+        Cmd::Next(opt) => Ok(client(&opt)?.skip_next().await?),
+        Cmd::Tune(opt) => tune(&opt).await,
+        Cmd::Watch(opt) => watch(&opt).await,
+    }
+}
+
+fn client(opt: &Opt) -> anyhow::Result<Client> {
+    let url = match &opt.server {
+        Some(url) => url.clone(),
+        None => match config::try_load() {
+            Some(config) => config.http.internal_url,
+            None => anyhow::bail!("no config.toml in the current directory, pass --server or set HAILSPLAY_URL"),
+        },
+    };
+
+    Ok(Client::new(url))
+}
+
+fn print_json(value: &impl Serialize) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string(value)?);
+    Ok(())
+}
+
+async fn status(opt: &Opt) -> anyhow::Result<()> {
+    let client = client(opt)?;
+    let player = client.status().await?;
+
+    let track = match &player.track {
+        Some(id) => client.track(id).await?,
+        None => None,
+    };
+
+    if opt.json {
+        #[derive(Serialize)]
+        struct Status {
+            player: PlayerStatus,
+            track: Option<TrackInfo>,
+        }
+
+        return print_json(&Status { player, track });
+    }
+
+    println!("{}", format_player(&player));
+
+    if let Some(track) = &track {
+        println!("{}", format_playing_track(track, &player));
+    }
+
+    Ok(())
+}
+
+async fn lyrics(opt: &Opt) -> anyhow::Result<()> {
+    let client = client(opt)?;
+    let player = client.status().await?;
+
+    let lyrics = match &player.track {
+        Some(id) => client.lyrics(id).await?,
+        None => None,
+    };
+
+    if opt.json {
+        return print_json(&lyrics);
+    }
+
+    let Some(lyrics) = lyrics else {
+        println!("no lyrics");
+        return Ok(());
+    };
+
+    let current = match &player.position {
+        Some(PlayPosition::Elapsed { time, .. }) => lyrics.line_at(*time),
+        _ => None,
+    };
+
+    for line in &lyrics.lines {
+        let marker = if current.is_some_and(|current| std::ptr::eq(current, line)) { ">" } else { " " };
+        println!("{marker} {:>5}  {}", format_time(line.time), line.text);
+    }
+
+    Ok(())
+}
+
+async fn queue(opt: &Opt) -> anyhow::Result<()> {
+    let client = client(opt)?;
+    let queue = client.queue().await?;
+
+    if opt.json {
+        return print_json(&queue);
+    }
+
+    let player = client.status().await?;
+    print_queue(&queue, &player);
+
+    Ok(())
+}
+
+async fn add(opt: &AddOpt) -> anyhow::Result<()> {
+    let client = client(&opt.opt)?;
+    let entries = if opt.entries.is_empty() { None } else { Some(opt.entries.clone()) };
+
+    let response = match Url::parse(&opt.target) {
+        Ok(url) => client.add(&url, entries).await?,
+        Err(_) => client.add_search(search_site(opt.soundcloud), &opt.target, entries).await?,
+    };
+
+    if opt.opt.json {
+        return print_json(&response);
+    }
+
+    for id in &response.mpd_ids {
+        match client.track(id).await? {
+            Some(track) => println!("added: {}", format_track(&track)),
+            None => println!("added: {}", opt.target),
+        }
+    }
+
+    Ok(())
+}
+
+async fn search(opt: &SearchOpt) -> anyhow::Result<()> {
+    let client = client(&opt.opt)?;
+    let results = client.search(search_site(opt.soundcloud), &opt.query).await?;
+
+    if opt.opt.json {
+        return print_json(&results);
+    }
+
+    for entry in results.entries.iter().flatten() {
+        let mut line = format!("{:>3}. {}", entry.index, entry.title);
+
+        if let Some(artist) = &entry.artist {
+            line += &format!(" - {artist}");
+        }
+
+        if let Some(duration) = entry.duration {
+            line += &format!(" [{}]", format_time(duration));
+        }
+
+        println!("{line}");
+    }
+
+    Ok(())
+}
+
+fn search_site(soundcloud: bool) -> SearchSite {
+    if soundcloud { SearchSite::Soundcloud } else { SearchSite::Youtube }
+}
+
+async fn tune(opt: &TuneOpt) -> anyhow::Result<()> {
+    let client = client(&opt.opt)?;
+
+    let url = match Url::parse(&opt.station) {
+        Ok(url) => url,
+        Err(_) => {
+            let stations = client.radio_stations().await?;
+
+            let station = stations.into_iter()
+                .find(|station| station.name.eq_ignore_ascii_case(&opt.station));
+
+            match station {
+                Some(station) => station.stream_url,
+                None => anyhow::bail!("no radio station named {:?}", opt.station),
+            }
+        }
+    };
+
+    client.tune(&url).await?;
+    Ok(())
+}
+
+async fn watch(opt: &Opt) -> anyhow::Result<()> {
+    let client = client(opt)?;
+    let mut events = client.events().await?;
+
+    log::info!("connected to hailsplay {}", events.hello().server_version);
+
+    // the queue listing needs the current track, which only arrives
+    // in player messages, so remember the last one we saw
+    let mut player = None;
+
+    while let Some(message) = events.next().await {
+        let message = message?;
+
+        if opt.json {
+            print_json(&message)?;
+            continue;
+        }
+
+        match message {
+            ServerMessage::Player { player: status } => {
+                println!("{}", format_player(&status));
+                player = Some(status);
+            }
+            ServerMessage::TrackChange { track: Some(track) } => {
+                println!("now playing: {}", format_track(&track));
+            }
+            ServerMessage::TrackChange { track: None } => {
+                println!("now playing: nothing");
+            }
+            ServerMessage::Queue { queue } => {
+                match &player {
+                    Some(player) => print_queue(&queue, player),
+                    None => println!("queue: {} items", queue.items.len()),
+                }
+            }
+            ServerMessage::Download { track, download } => {
+                println!("track {}: {}", track.0, format_download(&download));
+            }
+            ServerMessage::Error { message, .. } => {
+                eprintln!("error: {message}");
+            }
+            ServerMessage::Hello { .. } | ServerMessage::Ack { .. } => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn print_queue(queue: &Queue, player: &PlayerStatus) {
+    for item in &queue.items {
+        let marker = if Some(&item.id) == player.track.as_ref() { ">" } else { " " };
+
+        let download = match &item.download {
+            Some(download) => format!(" ({})", format_download(download)),
+            None => String::new(),
+        };
+
+        println!("{marker} {:>3}. {}{download}", item.position + 1, format_track(&item.track));
+    }
+}
+
+fn format_track(track: &TrackInfo) -> String {
+    let label = match &track.secondary_label {
+        Some(secondary) => format!("{} - {}", track.primary_label, secondary),
+        None => track.primary_label.clone(),
+    };
+
+    if track.live {
+        format!("{label} [live]")
+    } else {
+        label
+    }
+}
+
+// shows the current chapter in place of the secondary label
+fn format_playing_track(track: &TrackInfo, player: &PlayerStatus) -> String {
+    let chapter = match &player.position {
+        Some(PlayPosition::Elapsed { time, .. }) => track.chapter_at(*time),
+        _ => None,
+    };
+
+    match chapter {
+        Some(chapter) => format!("{} - {}", track.primary_label, chapter.title),
+        None => format_track(track),
+    }
+}
+
+fn format_download(download: &DownloadStatus) -> String {
+    match download.state {
+        DownloadState::Waiting => "waiting to download".to_owned(),
+        DownloadState::Retrying => match &download.error {
+            Some(error) => format!("retrying download: {error}"),
+            None => "retrying download".to_owned(),
+        },
+        DownloadState::Complete => "downloaded".to_owned(),
+        DownloadState::Failed => match &download.error {
+            Some(error) => format!("download failed: {error}"),
+            None => "download failed".to_owned(),
+        },
+        DownloadState::Downloading => match download.total_bytes {
+            Some(total) if total > 0 => {
+                let percent = download.downloaded_bytes as f64 / total as f64 * 100.0;
+                format!("downloading {percent:.0}%")
+            }
+            _ => format!("downloading {} bytes", download.downloaded_bytes),
+        },
+    }
+}
+
+fn format_player(player: &PlayerStatus) -> String {
+    let state = match player.state {
+        PlayState::Stopped => "stopped",
+        PlayState::Loading => "loading",
+        PlayState::Playing => "playing",
+    };
+
+    match &player.position {
+        Some(PlayPosition::Elapsed { time, duration }) => {
+            format!("{state} [{} / {}]", format_time(*time), format_time(*duration))
+        }
+        Some(PlayPosition::Streaming) => format!("{state} [streaming]"),
+        None => state.to_owned(),
+    }
+}
+
+fn format_time(seconds: f64) -> String {
+    let seconds = seconds as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
@@ -0,0 +1,236 @@
+//! Start and end offsets for playing only part of some media. These come
+//! from the url, as in `?t=1m30s` or `#t=90,120`, or are given explicitly
+//! when adding media.
+
+use url::{Url, form_urlencoded};
+
+/// Offsets from the start of the media in milliseconds, None meaning the
+/// start or end of the media itself
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Clip {
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+}
+
+impl Clip {
+    pub fn new(start: Option<u64>, end: Option<u64>) -> Clip {
+        // starting at zero is the same as not clipping the start at all
+        let start = start.filter(|start| *start > 0);
+
+        // an end before the start is nonsense, play to the end instead
+        let end = end.filter(|end| *end > start.unwrap_or(0));
+
+        Clip { start, end }
+    }
+
+    pub fn from_secs(start: Option<f64>, end: Option<f64>) -> Clip {
+        Clip::new(start.and_then(secs_to_millis), end.and_then(secs_to_millis))
+    }
+
+    /// Understands the timestamps of youtube (`?t=90`, `?t=1m30s`,
+    /// `?start=90&end=120`), soundcloud (`#t=1:30`) and media fragment
+    /// (`#t=90,120`) urls
+    pub fn from_url(url: &Url) -> Clip {
+        let mut start = None;
+        let mut end = None;
+
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "t" | "start" | "time_continue" => start = parse_timestamp(&value),
+                "end" => end = parse_timestamp(&value),
+                _ => {}
+            }
+        }
+
+        if let Some(fragment) = url.fragment() {
+            for (key, value) in form_urlencoded::parse(fragment.as_bytes()) {
+                if key != "t" {
+                    continue;
+                }
+
+                let value = value.strip_prefix("npt:").unwrap_or(&value);
+
+                match value.split_once(',') {
+                    Some((from, to)) => {
+                        start = parse_timestamp(from);
+                        end = parse_timestamp(to);
+                    }
+                    None => start = parse_timestamp(value),
+                }
+            }
+        }
+
+        Clip::new(start, end)
+    }
+
+    /// Converts a span of the original media, in seconds, to a span of the
+    /// clip. None if it falls outside the clip entirely
+    pub fn span_in_clip(&self, start: f64, end: f64) -> Option<(f64, f64)> {
+        let clip_start = self.start.unwrap_or(0) as f64 / 1000.0;
+        let clip_end = self.end.map_or(f64::INFINITY, |end| end as f64 / 1000.0);
+
+        let start = start.max(clip_start);
+        let end = end.min(clip_end);
+
+        (start < end).then_some((start - clip_start, end - clip_start))
+    }
+
+    /// Narrows the clip down to a span of it, in seconds from its start
+    pub fn narrow(&self, start: f64, end: f64) -> Clip {
+        let offset = self.start.unwrap_or(0) as f64 / 1000.0;
+        Clip::from_secs(Some(offset + start), Some(offset + end))
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.start.is_none() && self.end.is_none()
+    }
+
+    /// Formatted for yt-dlp's `--download-sections`, None if the whole
+    /// media is wanted
+    pub fn download_sections(&self) -> Option<String> {
+        if self.is_full() {
+            return None;
+        }
+
+        let start = format_secs(self.start.unwrap_or(0));
+
+        let end = match self.end {
+            Some(end) => format_secs(end),
+            None => "inf".to_owned(),
+        };
+
+        Some(format!("*{start}-{end}"))
+    }
+}
+
+fn secs_to_millis(secs: f64) -> Option<u64> {
+    (secs.is_finite() && secs >= 0.0).then(|| (secs * 1000.0).round() as u64)
+}
+
+fn format_secs(millis: u64) -> String {
+    match millis % 1000 {
+        0 => (millis / 1000).to_string(),
+        fraction => format!("{}.{fraction:03}", millis / 1000),
+    }
+}
+
+// accepts plain seconds ("90", "90.5s"), units ("1h2m3s") and clock
+// times ("1:30", "1:02:03")
+fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    let timestamp = timestamp.trim();
+
+    if timestamp.is_empty() {
+        return None;
+    }
+
+    if timestamp.contains(':') {
+        let mut secs = 0.0;
+
+        for part in timestamp.split(':') {
+            secs = secs * 60.0 + parse_part(part)?;
+        }
+
+        return secs_to_millis(secs);
+    }
+
+    if let Ok(secs) = timestamp.parse::<f64>() {
+        return secs_to_millis(secs);
+    }
+
+    let mut secs = 0.0;
+    let mut number = String::new();
+
+    for c in timestamp.chars() {
+        let unit = match c {
+            'h' => 3600.0,
+            'm' => 60.0,
+            's' => 1.0,
+            _ => {
+                number.push(c);
+                continue;
+            }
+        };
+
+        secs += parse_part(&number)? * unit;
+        number.clear();
+    }
+
+    if !number.is_empty() {
+        return None;
+    }
+
+    secs_to_millis(secs)
+}
+
+// each part of a timestamp on its own, so that "2m-30s" isn't 90 seconds
+fn parse_part(part: &str) -> Option<f64> {
+    part.parse::<f64>().ok().filter(|secs| *secs >= 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clip(url: &str) -> Clip {
+        Clip::from_url(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn parse_timestamp() {
+        let cases = [
+            ("90", 90_000),
+            ("90.5", 90_500),
+            (" 90 ", 90_000),
+            ("0", 0),
+            ("90s", 90_000),
+            ("1m30s", 90_000),
+            ("1h2m3s", 3_723_000),
+            ("1h", 3_600_000),
+            ("1.5m", 90_000),
+            ("1:30", 90_000),
+            ("1:02:03", 3_723_000),
+            ("1:30.25", 90_250),
+        ];
+
+        for (timestamp, millis) in cases {
+            assert_eq!(super::parse_timestamp(timestamp), Some(millis), "{timestamp:?}");
+        }
+    }
+
+    #[test]
+    fn parse_bad_timestamp() {
+        let bad = ["", "abc", "1m30", "m", "1x", "1::30", "1:", "-5", "1:-30", "2m-30s", "inf", "NaN"];
+
+        for timestamp in bad {
+            assert_eq!(super::parse_timestamp(timestamp), None, "{timestamp:?}");
+        }
+    }
+
+    #[test]
+    fn youtube_urls() {
+        assert_eq!(clip("https://www.youtube.com/watch?v=abc"), Clip::default());
+        assert_eq!(clip("https://www.youtube.com/watch?v=abc&t=90"), Clip::new(Some(90_000), None));
+        assert_eq!(clip("https://youtu.be/abc?t=1h2m3s"), Clip::new(Some(3_723_000), None));
+        assert_eq!(clip("https://www.youtube.com/embed/abc?start=90&end=120"), Clip::new(Some(90_000), Some(120_000)));
+        assert_eq!(clip("https://www.youtube.com/watch?v=abc&time_continue=15"), Clip::new(Some(15_000), None));
+
+        // shared from the very start, or mangled along the way
+        assert_eq!(clip("https://www.youtube.com/watch?v=abc&t=0"), Clip::default());
+        assert_eq!(clip("https://www.youtube.com/watch?v=abc&t=nonsense"), Clip::default());
+    }
+
+    #[test]
+    fn fragment_urls() {
+        assert_eq!(clip("https://soundcloud.com/artist/track#t=1:30"), Clip::new(Some(90_000), None));
+        assert_eq!(clip("https://example.com/a.mp3#t=90,120"), Clip::new(Some(90_000), Some(120_000)));
+        assert_eq!(clip("https://example.com/a.mp3#t=npt:1:30,2:00"), Clip::new(Some(90_000), Some(120_000)));
+        assert_eq!(clip("https://example.com/a.mp3#t=,120"), Clip::new(None, Some(120_000)));
+        assert_eq!(clip("https://example.com/a.mp3#track=2&t=10"), Clip::new(Some(10_000), None));
+
+        // the fragment wins over the query
+        assert_eq!(clip("https://example.com/a.mp3?t=5#t=10"), Clip::new(Some(10_000), None));
+
+        // an end before the start plays to the end
+        assert_eq!(clip("https://example.com/a.mp3#t=120,90"), Clip::new(Some(120_000), None));
+    }
+}
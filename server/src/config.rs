@@ -8,6 +8,10 @@ pub struct Config {
     pub http: Http,
     pub mpd: Mpd,
     pub storage: Storage,
+    #[serde(default)]
+    pub ytdlp: YtDlp,
+    #[serde(default)]
+    pub postprocess: PostProcess,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -29,6 +33,157 @@ pub struct Storage {
     pub database: PathBuf,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct YtDlp {
+    /// Path to the yt-dlp binary, looked up in PATH if not absolute
+    pub binary: PathBuf,
+    /// Maximum number of downloads to run at once, further downloads
+    /// wait their turn in order of their distance from the play position
+    pub concurrent_downloads: usize,
+    /// How long looked up metadata is reused for, in seconds
+    pub metadata_cache_ttl: u64,
+    /// Number of results listed for free text searches
+    pub search_results: u32,
+    #[serde(flatten)]
+    pub options: YtDlpOptions,
+    /// Per-site overrides, applied on top of `options` when the host of
+    /// the url matches `domain` or one of its subdomains
+    pub site: Vec<YtDlpSite>,
+}
+
+impl Default for YtDlp {
+    fn default() -> Self {
+        YtDlp {
+            binary: PathBuf::from("yt-dlp"),
+            concurrent_downloads: 2,
+            metadata_cache_ttl: 3600,
+            search_results: 5,
+            options: YtDlpOptions::default(),
+            site: Vec::new(),
+        }
+    }
+}
+
+impl YtDlp {
+    /// Resolves the options to use for `url`, more specific domains
+    /// take precedence over less specific ones
+    pub fn options_for(&self, url: &Url) -> YtDlpOptions {
+        let host = url.host_str().unwrap_or_default();
+
+        let mut sites = self.site.iter()
+            .filter(|site| site.matches(host))
+            .collect::<Vec<_>>();
+
+        sites.sort_by_key(|site| site.domain.len());
+
+        let mut options = self.options.clone();
+        for site in sites {
+            options.merge(&site.options);
+        }
+
+        options
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct YtDlpOptions {
+    /// Format selector passed as `--format`
+    pub format: Option<String>,
+    /// Format selector for live streams, which are played rather than
+    /// downloaded. Defaults to "bestaudio/best"
+    pub live_format: Option<String>,
+    /// Passed as `--audio-format`, eg. "opus" or "mp3"
+    pub audio_format: Option<String>,
+    /// Passed as `--audio-quality`, defaults to 0 (best)
+    pub audio_quality: Option<String>,
+    pub cookies: Option<PathBuf>,
+    /// Passed as `--limit-rate`, eg. "2M"
+    pub rate_limit: Option<String>,
+    /// SponsorBlock categories cut out of downloads with
+    /// `--sponsorblock-remove`, eg. ["music_offtopic"]
+    pub sponsorblock_remove: Option<Vec<String>>,
+    /// Languages of subtitles to download for lyrics, passed as
+    /// `--sub-langs`. yt-dlp's default of english if unset, an empty list
+    /// downloads none
+    pub subtitle_langs: Option<Vec<String>>,
+    pub proxy: Option<String>,
+    /// Appended to every yt-dlp invocation
+    pub extra_args: Vec<String>,
+}
+
+impl YtDlpOptions {
+    fn merge(&mut self, other: &YtDlpOptions) {
+        fn set<T: Clone>(value: &mut Option<T>, other: &Option<T>) {
+            if other.is_some() {
+                *value = other.clone();
+            }
+        }
+
+        set(&mut self.format, &other.format);
+        set(&mut self.live_format, &other.live_format);
+        set(&mut self.audio_format, &other.audio_format);
+        set(&mut self.audio_quality, &other.audio_quality);
+        set(&mut self.cookies, &other.cookies);
+        set(&mut self.rate_limit, &other.rate_limit);
+        set(&mut self.proxy, &other.proxy);
+        set(&mut self.sponsorblock_remove, &other.sponsorblock_remove);
+        set(&mut self.subtitle_langs, &other.subtitle_langs);
+        self.extra_args.extend(other.extra_args.iter().cloned());
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct PostProcess {
+    /// Path to the ffmpeg binary, looked up in PATH if not absolute
+    pub ffmpeg: PathBuf,
+    /// Measure the loudness of downloads as they're archived and tag them
+    /// with ReplayGain info
+    pub normalize_loudness: bool,
+    /// Cut silence from the start and end of downloads as they're
+    /// archived, can be overridden when adding media
+    pub trim_silence: bool,
+    /// Level below which audio counts as silence, in dB
+    pub silence_threshold: f64,
+}
+
+impl Default for PostProcess {
+    fn default() -> Self {
+        PostProcess {
+            ffmpeg: PathBuf::from("ffmpeg"),
+            normalize_loudness: true,
+            trim_silence: false,
+            silence_threshold: -50.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct YtDlpSite {
+    pub domain: String,
+    #[serde(flatten)]
+    pub options: YtDlpOptions,
+}
+
+impl YtDlpSite {
+    fn matches(&self, host: &str) -> bool {
+        in_domain(host, &self.domain)
+    }
+}
+
+/// Whether `host` is `domain` or one of its subdomains
+pub fn in_domain(host: &str, domain: &str) -> bool {
+    let domain = domain.trim_start_matches('.');
+
+    match host.strip_suffix(domain) {
+        Some("") => true,
+        Some(prefix) => prefix.ends_with('.'),
+        None => false,
+    }
+}
+
 fn try_config(path: &Path) -> Option<Config> {
     let config_toml = match std::fs::read_to_string(path) {
         Ok(contents) => {
@@ -51,12 +206,17 @@ fn try_config(path: &Path) -> Option<Config> {
 }
 
 pub fn load() -> Config {
-    let current_dir = std::env::current_dir().unwrap();
-
-    if let Some(config) = try_config(&current_dir.join("config.toml")) {
+    if let Some(config) = try_load() {
         return config;
     }
 
     log::error!("Missing config file");
     std::process::exit(1);
 }
+
+/// Like `load`, but returns None rather than exiting when there's no
+/// config file
+pub fn try_load() -> Option<Config> {
+    let current_dir = std::env::current_dir().unwrap();
+    try_config(&current_dir.join("config.toml"))
+}
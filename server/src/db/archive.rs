@@ -4,6 +4,7 @@ use rusqlite::{Connection, Row};
 use url::Url;
 
 use crate::api::archive::MediaStreamId;
+use crate::clip::Clip;
 use crate::ytdlp::Metadata;
 use crate::db::asset::AssetId;
 
@@ -18,6 +19,14 @@ pub struct ArchiveRecord {
     pub stream_uuid: MediaStreamId,
     pub thumbnail_id: Option<AssetId>,
     pub metadata: serde_json::Value,
+    /// Part of the media at canonical_url that was archived
+    pub clip: Clip,
+    /// ReplayGain track gain in dB, None until the loudness is measured
+    pub track_gain: Option<f64>,
+    pub track_peak: Option<f64>,
+    /// LRC file of lyrics made from the media's subtitles, relative to
+    /// the archive like `filename`
+    pub lyrics: Option<String>,
 }
 
 impl ArchiveRecord {
@@ -35,6 +44,10 @@ fn archive_record_from_row(row: &Row) -> Result<(ArchiveRecordId, ArchiveRecord)
         stream_uuid: MediaStreamId(row.get(4)?),
         thumbnail_id: Option::map(row.get(5)?, AssetId),
         metadata: row.get(6)?,
+        clip: Clip::new(row.get(7)?, row.get(8)?),
+        track_gain: row.get(9)?,
+        track_peak: row.get(10)?,
+        lyrics: row.get(11)?,
     };
     Ok((id, record))
 }
@@ -43,12 +56,26 @@ pub fn load_by_stream_uuid(conn: &mut Connection, id: &MediaStreamId)
     -> Result<(ArchiveRecordId, ArchiveRecord), rusqlite::Error>
 {
     conn.prepare(r"
-        SELECT id, path, canonical_url, archived_at, stream_uuid, thumbnail_id, metadata
+        SELECT id, path, canonical_url, archived_at, stream_uuid, thumbnail_id, metadata, clip_start, clip_end,
+            track_gain, track_peak, lyrics_path
         FROM archived_media
         WHERE stream_uuid = ?1
     ")?.query_row([&id.0], archive_record_from_row)
 }
 
+pub fn load_by_canonical_url(conn: &mut Connection, url: &Url, clip: Clip)
+    -> Result<(ArchiveRecordId, ArchiveRecord), rusqlite::Error>
+{
+    conn.prepare(r"
+        SELECT id, path, canonical_url, archived_at, stream_uuid, thumbnail_id, metadata, clip_start, clip_end,
+            track_gain, track_peak, lyrics_path
+        FROM archived_media
+        WHERE canonical_url = ?1 AND clip_start IS ?2 AND clip_end IS ?3
+        ORDER BY id DESC
+        LIMIT 1
+    ")?.query_row((url.as_str(), clip.start, clip.end), archive_record_from_row)
+}
+
 pub fn insert_media_record(conn: &mut Connection, record: ArchiveRecord)
     -> Result<ArchiveRecordId, rusqlite::Error>
 {
@@ -59,11 +86,41 @@ pub fn insert_media_record(conn: &mut Connection, record: ArchiveRecord)
         record.stream_uuid.0,
         record.thumbnail_id.map(|AssetId(id)| id),
         record.metadata,
+        record.clip.start,
+        record.clip.end,
+        record.track_gain,
+        record.track_peak,
+        record.lyrics,
     );
 
     conn.prepare(r"
-        INSERT INTO archived_media (path, canonical_url, archived_at, stream_uuid, thumbnail_id, metadata)
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+        INSERT INTO archived_media (path, canonical_url, archived_at, stream_uuid, thumbnail_id, metadata, clip_start, clip_end,
+            track_gain, track_peak, lyrics_path)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
         RETURNING id
     ")?.query_row(params, |row| row.get(0).map(ArchiveRecordId))
 }
+
+/// Archived media whose loudness hasn't been measured yet, or all of it
+/// with `include_measured`
+pub fn load_for_loudness(conn: &mut Connection, include_measured: bool)
+    -> Result<Vec<(ArchiveRecordId, ArchiveRecord)>, rusqlite::Error>
+{
+    conn.prepare(r"
+        SELECT id, path, canonical_url, archived_at, stream_uuid, thumbnail_id, metadata, clip_start, clip_end,
+            track_gain, track_peak, lyrics_path
+        FROM archived_media
+        WHERE track_gain IS NULL OR ?1
+        ORDER BY id ASC
+    ")?.query_map([include_measured], archive_record_from_row)?.collect()
+}
+
+pub fn set_loudness(conn: &mut Connection, id: ArchiveRecordId, track_gain: f64, track_peak: f64)
+    -> Result<(), rusqlite::Error>
+{
+    conn.execute(r"
+        UPDATE archived_media SET track_gain = ?2, track_peak = ?3 WHERE id = ?1
+    ", (id.0, track_gain, track_peak))?;
+
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, Row, ToSql};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
+use url::Url;
+
+use crate::api::archive::MediaStreamId;
+use crate::clip::Clip;
+use crate::postprocess::Processing;
+use crate::ytdlp::Metadata;
+
+/// A download which has been added to the queue but not yet archived
+#[derive(Debug)]
+pub struct DownloadJob {
+    pub stream_uuid: MediaStreamId,
+    pub url: Url,
+    pub created_at: DateTime<Utc>,
+    // what we knew about the media when it was added
+    pub metadata: serde_json::Value,
+    /// Why the download finally failed, if it has given up
+    pub error: Option<String>,
+    pub source: DownloadSource,
+    /// Part of the media to download, or all of it
+    pub clip: Clip,
+    pub processing: Processing,
+}
+
+/// How the media gets downloaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadSource {
+    YtDlp,
+    /// Fetched straight over HTTP, the url points at an audio file
+    Direct,
+}
+
+impl ToSql for DownloadSource {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let source = match self {
+            DownloadSource::YtDlp => "ytdlp",
+            DownloadSource::Direct => "direct",
+        };
+
+        Ok(source.into())
+    }
+}
+
+impl FromSql for DownloadSource {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "ytdlp" => Ok(DownloadSource::YtDlp),
+            "direct" => Ok(DownloadSource::Direct),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+// stored as json so that options can come and go without migrations
+impl ToSql for Processing {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let json = serde_json::to_string(self)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        Ok(json.into())
+    }
+}
+
+impl FromSql for Processing {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        serde_json::from_str(value.as_str()?)
+            .map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+impl DownloadJob {
+    pub fn parse_metadata(&self) -> Result<Metadata, serde_json::Error> {
+        serde_json::value::from_value(self.metadata.clone())
+    }
+}
+
+fn download_job_from_row(row: &Row) -> Result<DownloadJob, rusqlite::Error> {
+    Ok(DownloadJob {
+        stream_uuid: MediaStreamId(row.get(0)?),
+        url: row.get(1)?,
+        created_at: row.get(2)?,
+        metadata: row.get(3)?,
+        error: row.get(4)?,
+        source: row.get(5)?,
+        clip: Clip::new(row.get(6)?, row.get(7)?),
+        processing: row.get(8)?,
+    })
+}
+
+pub fn all_jobs(conn: &mut Connection) -> Result<Vec<DownloadJob>, rusqlite::Error> {
+    conn.prepare(r"
+        SELECT stream_uuid, url, created_at, metadata, error, source, clip_start, clip_end, processing
+        FROM download_jobs
+        ORDER BY id ASC
+    ")?.query_map([], download_job_from_row)?.collect()
+}
+
+pub fn insert_job(conn: &mut Connection, job: &DownloadJob) -> Result<(), rusqlite::Error> {
+    conn.execute(r"
+        INSERT INTO download_jobs (stream_uuid, url, created_at, metadata, error, source, clip_start, clip_end, processing)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+    ", (job.stream_uuid.0, job.url.to_string(), job.created_at, &job.metadata, &job.error, job.source, job.clip.start, job.clip.end, &job.processing))?;
+
+    Ok(())
+}
+
+pub fn set_job_error(conn: &mut Connection, id: &MediaStreamId, error: &str) -> Result<(), rusqlite::Error> {
+    conn.execute("UPDATE download_jobs SET error = ?2 WHERE stream_uuid = ?1", (&id.0, error))?;
+    Ok(())
+}
+
+pub fn delete_job(conn: &mut Connection, id: &MediaStreamId) -> Result<(), rusqlite::Error> {
+    conn.execute("DELETE FROM download_jobs WHERE stream_uuid = ?1", [&id.0])?;
+    Ok(())
+}
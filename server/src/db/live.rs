@@ -0,0 +1,62 @@
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OptionalExtension, Row};
+use url::Url;
+
+use crate::api::archive::MediaStreamId;
+use crate::ytdlp::Metadata;
+
+/// A live stream in the queue. Live streams are never downloaded, the
+/// player is pointed at a direct stream url resolved on demand
+#[derive(Debug)]
+pub struct LiveStream {
+    pub stream_uuid: MediaStreamId,
+    pub url: Url,
+    pub created_at: DateTime<Utc>,
+    pub metadata: serde_json::Value,
+}
+
+impl LiveStream {
+    pub fn parse_metadata(&self) -> Result<Metadata, serde_json::Error> {
+        serde_json::value::from_value(self.metadata.clone())
+    }
+}
+
+fn live_stream_from_row(row: &Row) -> Result<LiveStream, rusqlite::Error> {
+    Ok(LiveStream {
+        stream_uuid: MediaStreamId(row.get(0)?),
+        url: row.get(1)?,
+        created_at: row.get(2)?,
+        metadata: row.get(3)?,
+    })
+}
+
+pub fn insert(conn: &mut Connection, stream: &LiveStream) -> Result<(), rusqlite::Error> {
+    conn.execute(r"
+        INSERT INTO live_streams (stream_uuid, url, created_at, metadata)
+        VALUES (?1, ?2, ?3, ?4)
+    ", (stream.stream_uuid.0, stream.url.to_string(), stream.created_at, &stream.metadata))?;
+
+    Ok(())
+}
+
+/// Live streams added before `before`
+pub fn created_before(conn: &mut Connection, before: DateTime<Utc>) -> Result<Vec<MediaStreamId>, rusqlite::Error> {
+    conn.prepare(r"
+        SELECT stream_uuid
+        FROM live_streams
+        WHERE created_at < ?1
+    ")?.query_map([before], |row| Ok(MediaStreamId(row.get(0)?)))?.collect()
+}
+
+pub fn delete(conn: &mut Connection, id: &MediaStreamId) -> Result<(), rusqlite::Error> {
+    conn.execute("DELETE FROM live_streams WHERE stream_uuid = ?1", [&id.0])?;
+    Ok(())
+}
+
+pub fn load(conn: &mut Connection, id: &MediaStreamId) -> Result<Option<LiveStream>, rusqlite::Error> {
+    conn.prepare(r"
+        SELECT stream_uuid, url, created_at, metadata
+        FROM live_streams
+        WHERE stream_uuid = ?1
+    ")?.query_row([&id.0], live_stream_from_row).optional()
+}
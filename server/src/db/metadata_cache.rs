@@ -0,0 +1,31 @@
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OptionalExtension};
+
+/// Returns the cached info for `url` if it was fetched after `fresh_after`
+pub fn load(conn: &mut Connection, url: &str, fresh_after: DateTime<Utc>)
+    -> Result<Option<(DateTime<Utc>, serde_json::Value)>, rusqlite::Error>
+{
+    conn.prepare(r"
+        SELECT fetched_at, info
+        FROM metadata_cache
+        WHERE url = ?1 AND fetched_at > ?2
+    ")?.query_row((url, fresh_after), |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()
+}
+
+pub fn store(conn: &mut Connection, url: &str, fetched_at: DateTime<Utc>, info: &serde_json::Value)
+    -> Result<(), rusqlite::Error>
+{
+    conn.execute(r"
+        INSERT INTO metadata_cache (url, fetched_at, info)
+        VALUES (?1, ?2, ?3)
+        ON CONFLICT (url) DO UPDATE SET fetched_at = ?2, info = ?3
+    ", (url, fetched_at, info))?;
+
+    Ok(())
+}
+
+pub fn delete_stale(conn: &mut Connection, fresh_after: DateTime<Utc>) -> Result<(), rusqlite::Error> {
+    conn.execute("DELETE FROM metadata_cache WHERE fetched_at <= ?1", [fresh_after])?;
+    Ok(())
+}
@@ -64,4 +64,13 @@ macro_rules! migration {
 static MIGRATIONS: &[(&str, &str)] = &[
     migration!("000_create_schema"),
     migration!("001_create_archived_media"),
+    migration!("002_create_download_jobs"),
+    migration!("003_add_download_job_error"),
+    migration!("004_create_metadata_cache"),
+    migration!("005_create_live_streams"),
+    migration!("006_add_download_job_source"),
+    migration!("007_add_clip_range"),
+    migration!("008_add_archived_media_loudness"),
+    migration!("009_add_download_job_processing"),
+    migration!("010_add_archived_media_lyrics"),
 ];
@@ -1,5 +1,8 @@
 pub mod archive;
 pub mod asset;
+pub mod download;
+pub mod live;
+pub mod metadata_cache;
 pub mod radio;
 
 mod migrate;
@@ -46,3 +49,10 @@ pub async fn open(path: &Path) -> Result<Pool, OpenError> {
         Ok(Pool { conn: Arc::new(Mutex::new(conn)) })
     })
 }
+
+#[cfg(test)]
+pub fn open_in_memory() -> Pool {
+    let mut conn = Connection::open_in_memory().unwrap();
+    migrate::run(&mut conn).unwrap();
+    Pool { conn: Arc::new(Mutex::new(conn)) }
+}
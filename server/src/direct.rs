@@ -0,0 +1,328 @@
+//! Downloads media straight over HTTP, for urls which point at an audio
+//! file rather than a page yt-dlp needs to pick apart.
+
+use std::path::Path;
+
+use headers::{ContentType, HeaderMapExt};
+use mime::Mime;
+use percent_encoding::percent_decode_str;
+use reqwest::Response;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use url::Url;
+
+use crate::config;
+use crate::fs::SharedDir;
+use crate::tags;
+use crate::ytdlp::{self, DownloadError, DownloadHandle, Metadata, Progress};
+
+// enough of the file to find its tags in, see tags.rs
+const TAG_READ_SIZE: usize = 256 * 1024;
+
+// m3u playlists are small, anything bigger isn't one we want
+const MAX_PLAYLIST_SIZE: usize = 1024 * 1024;
+
+// sites yt-dlp has extractors for, which serve pages rather than audio
+// files, so there's no need to request them ourselves first
+const EXTRACTOR_DOMAINS: &[&str] = &[
+    "youtube.com",
+    "youtu.be",
+    "soundcloud.com",
+    "bandcamp.com",
+    "mixcloud.com",
+    "vimeo.com",
+    "twitch.tv",
+];
+
+/// What's at the other end of a url
+pub enum Probe {
+    /// An audio file, which can be downloaded directly
+    Audio(Box<Metadata>),
+    /// An M3U playlist of these urls
+    Playlist(Vec<Url>),
+    /// Something else, eg. a web page, left for yt-dlp to deal with
+    Other,
+}
+
+/// Requests `url` to see what it is, without downloading more than the
+/// response headers unless it's a playlist
+pub async fn probe(http: &reqwest::Client, url: &Url) -> Result<Probe, reqwest::Error> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Ok(Probe::Other);
+    }
+
+    let host = url.host_str().unwrap_or_default();
+
+    if EXTRACTOR_DOMAINS.iter().any(|domain| config::in_domain(host, domain)) {
+        return Ok(Probe::Other);
+    }
+
+    let mut response = http.get(url.clone()).send().await?.error_for_status()?;
+
+    let Some(content_type) = content_type(&response) else {
+        return Ok(Probe::Other);
+    };
+
+    if is_playlist(&content_type) {
+        let mut body = Vec::new();
+
+        while let Some(chunk) = response.chunk().await? {
+            if body.len() + chunk.len() > MAX_PLAYLIST_SIZE {
+                return Ok(Probe::Other);
+            }
+
+            body.extend_from_slice(&chunk);
+        }
+
+        let body = String::from_utf8_lossy(&body);
+
+        // HLS playlists share the content type, but list segments of a
+        // single stream rather than tracks
+        if body.contains("#EXT-X-") {
+            return Ok(Probe::Other);
+        }
+
+        return Ok(Probe::Playlist(parse_m3u(url, &body)));
+    }
+
+    // internet radio streams never end, leave them to yt-dlp
+    let is_radio = response.headers().keys()
+        .any(|name| name.as_str().starts_with("icy-"));
+
+    if content_type.type_() == mime::AUDIO && !is_radio {
+        return Ok(Probe::Audio(Box::new(preview_metadata(url, &content_type))));
+    }
+
+    Ok(Probe::Other)
+}
+
+fn content_type(response: &Response) -> Option<Mime> {
+    response.headers()
+        .typed_get::<ContentType>()
+        .map(Mime::from)
+}
+
+fn is_playlist(content_type: &Mime) -> bool {
+    matches!(content_type.essence_str(),
+        "audio/x-mpegurl" | "audio/mpegurl" | "application/x-mpegurl" | "application/vnd.apple.mpegurl")
+}
+
+fn parse_m3u(base: &Url, body: &str) -> Vec<Url> {
+    body.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| base.join(line).ok())
+        .collect()
+}
+
+// what we know before looking inside the file, the filename is the best
+// guess at a title until the tags have been read
+fn preview_metadata(url: &Url, content_type: &Mime) -> Metadata {
+    let filename = filename(url, content_type);
+
+    let title = Path::new(&filename).file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned());
+
+    let ext = Path::new(&filename).extension()
+        .map(|ext| ext.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    Metadata {
+        title: title.clone(),
+        full_title: title,
+        webpage_url: Some(url.clone()),
+        audio_ext: ext.clone(),
+        ext,
+        ..Metadata::default()
+    }
+}
+
+fn filename(url: &Url, content_type: &Mime) -> String {
+    let name = url.path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .and_then(|segment| percent_decode_str(segment).decode_utf8().ok())
+        .map(filenamify::filenamify)
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "download".to_owned());
+
+    let has_extension = Path::new(&name).extension().is_some();
+
+    match crate::mime::audio_extension(content_type) {
+        Some(ext) if !has_extension => format!("{name}.{ext}"),
+        _ => name,
+    }
+}
+
+/// Starts downloading `url` into `dir`. Like yt-dlp downloads, this
+/// returns once the download is underway and its metadata is known
+pub async fn start_download(http: &reqwest::Client, dir: SharedDir, url: &Url, preview: &Metadata)
+    -> Result<DownloadHandle, DownloadError>
+{
+    let mut response = http.get(url.clone()).send().await
+        .and_then(Response::error_for_status)
+        .map_err(DownloadError::http)?;
+
+    let content_type = content_type(&response)
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+
+    let filename = filename(url, &content_type);
+    let file = dir.claim_external_file(Path::new(&filename));
+    let total_bytes = response.content_length();
+
+    let mut out = tokio::fs::File::create(file.path()).await
+        .map_err(DownloadError::write)?;
+
+    // read the start of the file for its tags before handing it over
+    let mut head = Vec::new();
+
+    while head.len() < TAG_READ_SIZE {
+        let Some(chunk) = response.chunk().await.map_err(DownloadError::http)? else {
+            break;
+        };
+
+        write_chunk(&mut out, &chunk).await?;
+        head.extend_from_slice(&chunk);
+    }
+
+    let metadata = metadata(url, &content_type, preview, tags::read(&head));
+    let downloaded_bytes = u64::try_from(head.len()).unwrap_or(u64::MAX);
+
+    // written alongside like yt-dlp's info json, it's archived with the media
+    let metadata_file = dir.claim_external_file(Path::new(&format!("{filename}.info.json")));
+    let metadata_json = serde_json::to_vec(&metadata).expect("serialize metadata");
+    tokio::fs::write(metadata_file.path(), metadata_json).await
+        .map_err(DownloadError::write)?;
+
+    let (progress_tx, progress_rx) = watch::channel(Progress {
+        downloaded_bytes,
+        total_bytes,
+        total_bytes_estimate: None,
+    });
+
+    let cancel = CancellationToken::new();
+    let download = run_download(response, out, progress_tx, cancel.clone());
+
+    // direct downloads are served as they are, the file never changes
+    let (_, file) = watch::channel(file.into_shared());
+
+    Ok(DownloadHandle {
+        dir,
+        file,
+        thumbnail: None,
+        subtitles: None,
+        other_subtitles: Vec::new(),
+        metadata,
+        metadata_file: metadata_file.into_shared(),
+        progress: progress_rx,
+        complete: ytdlp::spawn_download(download),
+        cancel,
+    })
+}
+
+fn metadata(url: &Url, content_type: &Mime, preview: &Metadata, tags: tags::Tags) -> Metadata {
+    let fallback = preview_metadata(url, content_type);
+
+    let title = tags.title
+        .or_else(|| preview.title.clone())
+        .or(fallback.title);
+
+    Metadata {
+        title: title.clone(),
+        full_title: title,
+        uploader: tags.artist.or_else(|| preview.uploader.clone()),
+        genre: tags.genre.or_else(|| preview.genre.clone()),
+        webpage_url: Some(url.clone()),
+        ext: fallback.ext,
+        audio_ext: fallback.audio_ext,
+        ..preview.clone()
+    }
+}
+
+async fn run_download(
+    mut response: Response,
+    mut out: tokio::fs::File,
+    progress_tx: watch::Sender<Progress>,
+    cancel: CancellationToken,
+) -> Result<(), DownloadError> {
+    let total_bytes = progress_tx.borrow().total_bytes;
+    let mut downloaded_bytes = progress_tx.borrow().downloaded_bytes;
+
+    loop {
+        let chunk = tokio::select! {
+            chunk = response.chunk() => chunk.map_err(DownloadError::http)?,
+            () = cancel.cancelled() => return Err(DownloadError::Cancelled),
+        };
+
+        let Some(chunk) = chunk else {
+            break;
+        };
+
+        write_chunk(&mut out, &chunk).await?;
+        downloaded_bytes += u64::try_from(chunk.len()).unwrap_or(u64::MAX);
+
+        let _ = progress_tx.send(Progress {
+            downloaded_bytes,
+            total_bytes,
+            total_bytes_estimate: None,
+        });
+    }
+
+    if let Some(expected) = total_bytes {
+        if downloaded_bytes < expected {
+            return Err(DownloadError::Incomplete { downloaded: downloaded_bytes, expected });
+        }
+    }
+
+    let _ = progress_tx.send(Progress {
+        downloaded_bytes,
+        total_bytes: Some(downloaded_bytes),
+        total_bytes_estimate: None,
+    });
+
+    Ok(())
+}
+
+// streams read the file as it's written, so each chunk must reach the
+// file before progress says it's there
+async fn write_chunk(out: &mut tokio::fs::File, chunk: &[u8]) -> Result<(), DownloadError> {
+    out.write_all(chunk).await.map_err(DownloadError::write)?;
+    out.flush().await.map_err(DownloadError::write)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_m3u(body: &str) -> Vec<String> {
+        let base = Url::parse("https://example.com/music/list.m3u").unwrap();
+        super::parse_m3u(&base, body).into_iter().map(String::from).collect()
+    }
+
+    #[test]
+    fn parse_extended_m3u() {
+        let body = "#EXTM3U\r\n\
+            #EXTINF:123,Artist - Title\r\n  one.mp3  \r\n\r\n\
+            #EXTINF:-1,Some Radio\r\nhttp://radio.example.org:8000/stream\r\n";
+
+        assert_eq!(parse_m3u(body), ["https://example.com/music/one.mp3", "http://radio.example.org:8000/stream"]);
+        assert!(parse_m3u("#EXTM3U\n").is_empty());
+        assert!(parse_m3u("").is_empty());
+    }
+
+    #[test]
+    fn parse_relative_entries() {
+        assert_eq!(parse_m3u("one.mp3\n../other/two.mp3\n/three.mp3"), [
+            "https://example.com/music/one.mp3",
+            "https://example.com/other/two.mp3",
+            "https://example.com/three.mp3",
+        ]);
+
+        assert_eq!(parse_m3u("with space.mp3"), ["https://example.com/music/with%20space.mp3"]);
+    }
+
+    #[test]
+    fn bad_entries_are_skipped() {
+        assert_eq!(parse_m3u("http://[bad\nok.mp3\n"), ["https://example.com/music/ok.mp3"]);
+    }
+}
@@ -3,6 +3,9 @@ use axum::http::StatusCode;
 use axum::Json;
 use serde::Serialize;
 
+use crate::api::NoSearchResults;
+use crate::ytdlp::YtDlpError;
+
 pub type AppResult<T> = Result<T, AppError>;
 
 #[derive(Debug)]
@@ -13,8 +16,43 @@ struct ErrorInfo {
     message: String,
 }
 
+impl AppError {
+    // errors caused by the media the client asked for, rather than by us
+    fn client_error(&self) -> Option<(StatusCode, String)> {
+        if let Some(error) = self.find::<NoSearchResults>() {
+            return Some((StatusCode::NOT_FOUND, error.to_string()));
+        }
+
+        let error = self.find::<YtDlpError>()?;
+
+        let status = match error {
+            YtDlpError::UnsupportedUrl => StatusCode::BAD_REQUEST,
+            YtDlpError::Private => StatusCode::FORBIDDEN,
+            YtDlpError::AgeRestricted => StatusCode::FORBIDDEN,
+            YtDlpError::GeoBlocked => StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+            YtDlpError::Removed => StatusCode::GONE,
+            YtDlpError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            YtDlpError::Network(_) | YtDlpError::Other(_) | YtDlpError::Unknown => return None,
+        };
+
+        Some((status, error.to_string()))
+    }
+
+    fn find<E: std::error::Error + Send + Sync + 'static>(&self) -> Option<&E> {
+        self.0.chain().find_map(|cause| cause.downcast_ref::<E>())
+    }
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        if let Some((status, message)) = self.client_error() {
+            log::warn!("http request failed: {status}: {:?}", self.0);
+
+            let error = ErrorInfo { message };
+
+            return (status, Json(error)).into_response();
+        }
+
         log::error!("http request error: {:?}\n{}", self.0, self.0.backtrace());
 
         let error = ErrorInfo {
@@ -14,6 +14,10 @@ impl WorkingDirectory {
         Ok(WorkingDirectory { path })
     }
 
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub async fn create_dir(&self, name: impl AsRef<Path>) -> io::Result<OwnedDir> {
         let dir = self.path.join(name.as_ref());
         tokio::fs::create_dir(&dir).await?;
@@ -84,10 +88,44 @@ impl SharedFile {
     }
 }
 
+/// Hard links a file, falling back to copying it when `from` and `to` are
+/// on different filesystems. Either way, `to` only appears once complete
+/// and `from` stays where it is.
+pub async fn link_file(from: &Path, to: &Path) -> io::Result<()> {
+    match tokio::fs::hard_link(from, to).await {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+        Err(e) => return Err(e),
+    }
+
+    copy_into_place(from, to).await
+}
+
+// copies alongside the destination first, so it can be renamed into place
+async fn copy_into_place(from: &Path, to: &Path) -> io::Result<()> {
+    let mut partial = to.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+
+    let result = async {
+        tokio::fs::copy(from, &partial).await?;
+        tokio::fs::rename(&partial, to).await
+    }.await;
+
+    if let Err(e) = result {
+        let _ = tokio::fs::remove_file(&partial).await;
+        return Err(e);
+    }
+
+    Ok(())
+}
+
 impl Drop for OwnedDir {
     fn drop(&mut self) {
         match std::fs::remove_dir(&self.path) {
             Ok(()) => {}
+            // already cleaned up by someone else
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
             Err(e) => {
                 log::warn!("error removing directory: {e:?}");
             }
@@ -99,9 +137,73 @@ impl Drop for OwnedFile {
     fn drop(&mut self) {
         match std::fs::remove_file(&self.path) {
             Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
             Err(e) => {
                 log::warn!("error removing file: {e:?}");
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hailsplay-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn link_file_keeps_source() {
+        let dir = temp_dir();
+        let (from, to) = (dir.join("download.opus"), dir.join("archived.opus"));
+        std::fs::write(&from, "media").unwrap();
+
+        link_file(&from, &to).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&from).unwrap(), "media");
+        assert_eq!(std::fs::metadata(&to).unwrap().ino(), std::fs::metadata(&from).unwrap().ino());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn copy_into_place_leaves_no_partial_file() {
+        let dir = temp_dir();
+        let (from, to) = (dir.join("download.opus"), dir.join("archived.opus"));
+        std::fs::write(&from, "media").unwrap();
+
+        copy_into_place(&from, &to).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&to).unwrap(), "media");
+        assert!(from.exists());
+
+        let mut names = std::fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["archived.opus", "download.opus"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_copy_cleans_up() {
+        let dir = temp_dir();
+        let (from, to) = (dir.join("download.opus"), dir.join("archived.opus"));
+        std::fs::write(&from, "media").unwrap();
+
+        // copying works, renaming over a directory doesn't
+        std::fs::create_dir(&to).unwrap();
+        copy_into_place(&from, &to).await.unwrap_err();
+
+        assert!(!dir.join("archived.opus.partial").exists());
+        assert!(from.exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
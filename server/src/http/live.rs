@@ -0,0 +1,16 @@
+use axum::extract::{Path, State};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::http::StatusCode;
+
+use crate::api::archive::MediaStreamId;
+use crate::error::AppResult;
+use crate::App;
+
+/// Redirects the player to the current direct url of a live stream. The
+/// url mpd holds stays the same while the one behind it is refreshed
+pub async fn stream(app: State<App>, Path(id): Path<MediaStreamId>) -> AppResult<Response> {
+    match app.live_streams().stream_url(id).await? {
+        Some(url) => Ok(Redirect::temporary(url.as_str()).into_response()),
+        None => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
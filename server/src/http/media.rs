@@ -3,6 +3,7 @@ use std::task::{Context, Poll};
 use std::pin::Pin;
 use std::cmp;
 
+use axum::body::StreamBody;
 use axum::response::Response;
 use axum::{extract::{State, Path}, response::IntoResponse, TypedHeader};
 use axum::http::StatusCode;
@@ -10,15 +11,17 @@ use derive_more::From;
 use headers::{Range, ContentType};
 use pin_project::pin_project;
 use tokio::{fs::File, io::ReadBuf};
-use tokio::sync::watch::Receiver;
+use tokio::sync::oneshot;
 use tokio::io::AsyncRead;
 use tokio_stream::wrappers::WatchStream;
-use futures::{StreamExt, stream::Fuse, ready};
+use tokio_util::io::ReaderStream;
+use futures::{FutureExt, StreamExt, future::Shared, stream::Fuse, ready};
 
 use crate::{App, Config};
 use crate::api::archive::{MediaStreamId, RecordKind, MetadataParseError};
-use crate::ytdlp::Progress;
+use crate::ytdlp::{DownloadError, DownloadHandle, Progress};
 use crate::error::AppError;
+use crate::fs::SharedFile;
 
 use axum_range::{Ranged, RangeNotSatisfiable, RangeBody, AsyncSeekStart, KnownSize};
 
@@ -40,37 +43,77 @@ pub async fn stream(
 
     log::info!("Serving stream title={:?} id={:?}", metadata.title, media_id);
 
-    let content_type = TypedHeader(ContentType::from(media.content_type()));
-
-    let response = ranged_response(&media, range, app.config()).await?;
-
-    Ok((content_type, response).into_response())
+    ranged_response(&media, range, app.config()).await
 }
 
-async fn ranged_response(media: &RecordKind, range: Option<Range>, config: &Config) -> io::Result<Response> {
-    let path = media.disk_path(config);
-    let file = tokio::fs::File::open(path).await?;
-
+async fn ranged_response(media: &RecordKind, range: Option<Range>, config: &Config)
+    -> Result<Response, MediaStreamError>
+{
     match media {
-        RecordKind::Archive(_, _) => {
+        RecordKind::Archive(_, record) => {
+            let path = config.storage.archive.join(&record.filename);
+            let content_type = content_type(&path);
+            let file = tokio::fs::File::open(path).await?;
             let body = KnownSize::file(file).await?;
-            Ok(Ranged::new(range, body).into_response())
+            Ok((content_type, Ranged::new(range, body)).into_response())
         }
         RecordKind::Memory(record) => {
-            let progress = record.download.progress.clone();
-            let body = StreamingDownload::new(file, progress);
-            Ok(Ranged::new(range, body).into_response())
+            // mpd asks for the stream when it's about to play it, which
+            // may be before the download has had its turn to start
+            let download = record.started().await?;
+            let (download_file, file) = open_download(&download).await?;
+            let content_type = content_type(download_file.path());
+
+            if let Some(Ok(Ok(()))) = download.complete.peek() {
+                // the file is final, whichever one we opened
+                let body = KnownSize::file(file).await?;
+                return Ok((content_type, Ranged::new(range, body)).into_response());
+            }
+
+            let body = StreamingDownload::new(file, &download);
+
+            if body.total_bytes().is_none() {
+                // ranges can't be served without knowing the size, so
+                // stream from the start until the download finishes
+                let body = StreamBody::new(ReaderStream::new(body));
+                return Ok((content_type, body).into_response());
+            }
+
+            Ok((content_type, Ranged::new(range, body)).into_response())
         }
     }
 }
 
+async fn open_download(download: &DownloadHandle) -> io::Result<(SharedFile, File)> {
+    let download_file = download.file();
+
+    match File::open(download_file.path()).await {
+        Ok(file) => { return Ok((download_file, file)); }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => { return Err(e); }
+    }
+
+    // yt-dlp deletes the original once it has extracted the audio from it,
+    // but the extracted file only takes its place once yt-dlp exits
+    let _ = download.complete.clone().await;
+
+    let download_file = download.file();
+    let file = File::open(download_file.path()).await?;
+    Ok((download_file, file))
+}
+
+fn content_type(path: &std::path::Path) -> TypedHeader<ContentType> {
+    TypedHeader(ContentType::from(crate::mime::from_path(path)))
+}
+
 #[derive(From)]
 pub enum MediaStreamError {
     NotFound,
     RangeNotSatisfiable(RangeNotSatisfiable),
     Io(io::Error),
     Database(rusqlite::Error),
-    ParseMetadata(MetadataParseError)
+    ParseMetadata(MetadataParseError),
+    Download(DownloadError),
 }
 
 impl IntoResponse for MediaStreamError {
@@ -79,6 +122,7 @@ impl IntoResponse for MediaStreamError {
             MediaStreamError::Io(e) => AppError::from(e).into_response(),
             MediaStreamError::Database(e) => AppError::from(e).into_response(),
             MediaStreamError::ParseMetadata(e) => AppError::from(e).into_response(),
+            MediaStreamError::Download(e) => AppError::from(e).into_response(),
             MediaStreamError::NotFound => StatusCode::NOT_FOUND.into_response(),
             MediaStreamError::RangeNotSatisfiable(response) => response.into_response(),
         }
@@ -94,13 +138,13 @@ struct StreamingDownload {
 }
 
 impl StreamingDownload {
-    pub fn new(file: File, progress: Receiver<Progress>) -> Self {
-        let progress = PollProgress::new(progress);
+    pub fn new(file: File, download: &DownloadHandle) -> Self {
+        let progress = PollProgress::new(download);
         let seek = Seek::At(0);
         StreamingDownload { file, seek, progress }
     }
 
-    fn total_bytes(&self) -> u64 {
+    fn total_bytes(&self) -> Option<u64> {
         self.progress.current.total_bytes
     }
 }
@@ -113,13 +157,14 @@ enum Seek {
 
 impl RangeBody for StreamingDownload {
     fn byte_size(&self) -> u64 {
-        self.total_bytes()
+        // only served as a range body once the size is known
+        self.total_bytes().unwrap_or(self.progress.current.downloaded_bytes)
     }
 }
 
 impl AsyncSeekStart for StreamingDownload {
     fn start_seek(self: Pin<&mut Self>, position: u64) -> io::Result<()> {
-        let seek_to = cmp::min(self.total_bytes(), position);
+        let seek_to = self.total_bytes().map_or(position, |total| cmp::min(total, position));
         let this = self.project();
 
         if let Seek::At(_) = this.seek {
@@ -144,7 +189,7 @@ impl AsyncSeekStart for StreamingDownload {
         }
 
         if let Seek::SeekTo(pos) = *this.seek {
-            ready!(this.progress.poll_ready(cx, pos));
+            ready!(this.progress.poll_ready(cx, pos))?;
             AsyncSeekStart::start_seek(this.file.as_mut(), pos)?;
             *this.seek = Seek::Seeking(pos);
         }
@@ -172,7 +217,7 @@ impl AsyncRead for StreamingDownload {
             )));
         };
 
-        ready!(this.progress.poll_ready(cx, pos));
+        ready!(this.progress.poll_ready(cx, pos))?;
 
         let filled_before_read = buf.filled().len();
         let () = ready!(AsyncRead::poll_read(this.file.as_mut(), cx, buf))?;
@@ -190,29 +235,43 @@ impl AsyncRead for StreamingDownload {
 struct PollProgress {
     current: Progress,
     watch: Fuse<WatchStream<Progress>>,
+    complete: Shared<oneshot::Receiver<Result<(), DownloadError>>>,
 }
 
 impl PollProgress {
-    pub fn new(watch: Receiver<Progress>) -> Self {
+    pub fn new(download: &DownloadHandle) -> Self {
+        let watch = download.progress.clone();
         let current = watch.borrow().clone();
         let watch = WatchStream::new(watch).fuse();
-        PollProgress { current, watch }
+        let complete = download.complete.clone();
+        PollProgress { current, watch, complete }
     }
 
-    pub fn poll_ready(&mut self, cx: &mut Context<'_>, position: u64) -> Poll<()> {
+    /// Resolves once `position` has been downloaded, or fails if the
+    /// download ends before getting there
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>, position: u64) -> Poll<io::Result<()>> {
         loop {
             if self.current.complete() {
-                return Poll::Ready(());
+                return Poll::Ready(Ok(()));
             }
 
             if position < self.current.downloaded_bytes {
-                return Poll::Ready(());
+                return Poll::Ready(Ok(()));
             }
 
             match ready!(self.watch.poll_next_unpin(cx)) {
                 Some(progress) => { self.current = progress; }
-                None => { return Poll::Ready(()); }
+                None => { break; }
             }
         }
+
+        // the download has stopped, find out whether it finished or died
+        // part way. reading on from a failed download would hand the
+        // client a truncated file, so fail the body instead
+        match ready!(self.complete.poll_unpin(cx)) {
+            Ok(Ok(())) => Poll::Ready(Ok(())),
+            Ok(Err(e)) => Poll::Ready(Err(io::Error::other(e))),
+            Err(_) => Poll::Ready(Err(io::Error::other("download task terminated"))),
+        }
     }
 }
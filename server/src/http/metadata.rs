@@ -1,29 +1,74 @@
-use axum::extract::Query;
+use axum::extract::{Query, State};
 use axum::Json;
-use hailsplay_protocol::Metadata;
+use hailsplay_protocol::{Metadata, PlaylistEntry, SearchSite};
+use reqwest::StatusCode;
 use serde::Deserialize;
 use url::Url;
 
+use crate::App;
 use crate::error::AppResult;
-use crate::ytdlp;
+use crate::ytdlp::{self, Info};
 
 #[derive(Deserialize)]
 pub struct MetadataParams {
-    url: Url,
+    url: Option<Url>,
+    query: Option<String>,
+    #[serde(default)]
+    site: SearchSite,
 }
 
-pub async fn metadata(params: Query<MetadataParams>) -> AppResult<Json<Metadata>> {
-    log::info!("Fetching metadata for {}", params.url);
-    let metadata = request_metadata(&params.url).await?;
-    Ok(Json(metadata))
+pub async fn metadata(app: State<App>, params: Query<MetadataParams>)
+    -> AppResult<Result<Json<Metadata>, StatusCode>>
+{
+    let metadata = match (&params.url, &params.query) {
+        (Some(url), None) => {
+            log::info!("Fetching metadata for {url}");
+            let info = app.metadata_cache().fetch(url).await?;
+            to_metadata(info, url.as_str())
+        }
+        (None, Some(query)) => {
+            // results aren't cached, they're only looked at once before
+            // picking one to add
+            log::info!("Searching {:?} for {query:?}", params.site);
+            let info = ytdlp::search(&app.config().ytdlp, params.site, query).await?;
+            to_metadata(info, query)
+        }
+        (None, None) | (Some(_), Some(_)) => return Ok(Err(StatusCode::BAD_REQUEST)),
+    };
+
+    Ok(Ok(Json(metadata)))
 }
 
-async fn request_metadata(url: &Url) -> anyhow::Result<Metadata> {
-    let metadata = ytdlp::fetch_metadata(url).await?;
+fn to_metadata(info: Info, fallback_title: &str) -> Metadata {
+    match info {
+        Info::Single(metadata) => Metadata {
+            title: metadata.title.unwrap_or_else(|| fallback_title.to_owned()),
+            artist: metadata.uploader,
+            thumbnail: metadata.thumbnail,
+            entries: None,
+        },
+        Info::Playlist(playlist) => {
+            let entries = playlist.entries.iter()
+                .enumerate()
+                .filter_map(|(index, entry)| {
+                    let url = entry.url()?;
+                    Some(PlaylistEntry {
+                        index: u32::try_from(index).ok()?,
+                        title: entry.title.clone().unwrap_or_else(|| url.to_string()),
+                        url,
+                        artist: entry.uploader.clone().or_else(|| entry.channel.clone()),
+                        thumbnail: ytdlp::best_thumbnail(&entry.thumbnails).cloned(),
+                        duration: entry.duration,
+                    })
+                })
+                .collect();
 
-    Ok(Metadata {
-        title: metadata.title.unwrap_or_else(|| url.to_string()),
-        artist: metadata.uploader,
-        thumbnail: metadata.thumbnail,
-    })
+            Metadata {
+                title: playlist.title.unwrap_or_else(|| fallback_title.to_owned()),
+                artist: playlist.uploader,
+                thumbnail: ytdlp::best_thumbnail(&playlist.thumbnails).cloned(),
+                entries: Some(entries),
+            }
+        }
+    }
 }
@@ -4,6 +4,7 @@ use axum::routing::{get, post};
 use crate::App;
 
 pub mod assets;
+pub mod live;
 pub mod media;
 pub mod metadata;
 pub mod player;
@@ -16,9 +17,11 @@ pub fn routes(app: App) -> Router {
         .route("/api/queue", post(queue::add))
         .route("/api/queue", get(queue::index))
         .route("/api/queue/:id", get(queue::show))
+        .route("/api/queue/:id/lyrics", get(queue::lyrics))
         .route("/api/radio/tune", post(radio::tune))
         .route("/api/radio/stations", get(radio::stations))
         .route("/api/metadata", get(metadata::metadata))
+        .route("/api/player", get(player::status))
         .route("/api/player/play", post(player::play))
         .route("/api/player/pause", post(player::pause))
         .route("/api/player/stop", post(player::stop))
@@ -26,6 +29,7 @@ pub fn routes(app: App) -> Router {
         .route("/api/player/skip-back", post(player::skip_back))
         .route("/assets/:id/:digest/:filename", get(assets::file))
         .route("/media/:id/stream", get(media::stream))
+        .route("/live/:id/stream", get(live::stream))
         .route("/ws", get(ws::handler))
         .with_state(app)
 }
@@ -1,7 +1,14 @@
 use axum::extract::State;
 use axum::Json;
 
-use crate::{error::AppResult, App};
+use hailsplay_protocol::PlayerStatus;
+
+use crate::{api, error::AppResult, App};
+
+pub async fn status(app: State<App>) -> AppResult<Json<PlayerStatus>> {
+    let mut session = app.session().await?;
+    Ok(Json(api::status(&mut session).await?))
+}
 
 pub async fn play(app: State<App>) -> AppResult<Json<()>> {
     let mut session = app.session().await?;
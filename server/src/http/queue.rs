@@ -1,13 +1,13 @@
-use anyhow::Result;
 use axum::{Json, debug_handler};
 use axum::extract::{Path, State};
 
-use hailsplay_protocol::{TrackId, TrackInfo, Queue, AddResponse, AddParams};
+use hailsplay_protocol::{TrackId, TrackInfo, Queue, AddResponse, AddParams, Lyrics};
 use reqwest::StatusCode;
 
+use crate::clip::Clip;
 use crate::error::AppResult;
-use crate::mpd::{self, Mpd};
-use crate::api;
+use crate::postprocess::Processing;
+use crate::api::{self, AddOptions};
 use crate::App;
 
 #[debug_handler]
@@ -28,32 +28,44 @@ pub async fn show(app: State<App>, Path(track_id): Path<TrackId>)
     }
 }
 
-#[axum::debug_handler]
-pub async fn add(app: State<App>, data: Json<AddParams>) -> AppResult<Json<AddResponse>> {
-    let record = app.archive().add_url(&data.url).await?;
-
-    let metadata = record.parse_metadata()?;
-    let stream_url = record.internal_stream_url(app.config());
-
-    log::info!("Adding {}", metadata.title
-        .unwrap_or_else(|| data.url.to_string()));
-
-    let mut mpd = app.mpd().await?;
-    let mpd_id = mpd.addid(&stream_url).await?;
+pub async fn lyrics(app: State<App>, Path(track_id): Path<TrackId>)
+    -> AppResult<Result<Json<Lyrics>, StatusCode>>
+{
+    let mut session = app.session().await?;
 
-    if should_autoplay(&mut mpd, &mpd_id).await? {
-        mpd.play().await?;
+    match api::lyrics(&mut session, &track_id).await? {
+        None => Ok(Err(StatusCode::NOT_FOUND)),
+        Some(lyrics) => Ok(Ok(Json(lyrics))),
     }
-
-    Ok(Json(AddResponse { mpd_id: mpd_id.into() }))
 }
 
-async fn should_autoplay(mpd: &mut Mpd, added_id: &mpd::Id) -> Result<bool> {
-    let playlist = mpd.playlistinfo().await?;
+#[axum::debug_handler]
+pub async fn add(app: State<App>, data: Json<AddParams>)
+    -> AppResult<Result<Json<AddResponse>, StatusCode>>
+{
+    let mut session = app.session().await?;
+    let entries = data.entries.as_deref();
 
-    if playlist.items.len() != 1 {
-        return Ok(false);
-    }
+    let clip = (data.start.is_some() || data.end.is_some())
+        .then(|| Clip::from_secs(data.start, data.end));
+
+    let options = AddOptions {
+        clip,
+        split_chapters: data.split_chapters,
+        processing: Processing {
+            trim_silence: data.trim_silence,
+            sponsorblock_remove: data.sponsorblock_remove.clone(),
+        },
+    };
+
+    let mpd_ids = match (&data.url, &data.query) {
+        (Some(url), None) => api::add(&mut session, url, entries, options).await?,
+        (None, Some(query)) => api::add_search(&mut session, data.site, query, entries, options).await?,
+        (None, None) | (Some(_), Some(_)) => return Ok(Err(StatusCode::BAD_REQUEST)),
+    };
 
-    Ok(playlist.items[0].id == *added_id)
+    Ok(Ok(Json(AddResponse {
+        mpd_id: mpd_ids[0].clone(),
+        mpd_ids,
+    })))
 }
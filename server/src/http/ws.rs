@@ -1,13 +1,30 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use axum::extract::{ConnectInfo, WebSocketUpgrade};
-use axum::extract::ws::{WebSocket, Message};
+use axum::extract::ws::{WebSocket, Message, CloseFrame};
 use axum::response::IntoResponse;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use tokio::sync::Mutex;
 
 use crate::App;
-use crate::mpd::MpdEvent;
-use crate::api::{self, Session};
-use hailsplay_protocol::{ClientMessage, ServerMessage, TrackId};
+use crate::mpd::{MpdEvent, Playlist, Seconds};
+use crate::api::{self, metadata, AddOptions, Session};
+use hailsplay_protocol::{ClientMessage, Command, CommandResponse, ServerMessage, TrackId};
+use hailsplay_protocol::{DownloadState, DownloadStatus};
+use hailsplay_protocol::{Capabilities, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
+
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+// minimum interval between download progress updates sent to a client
+const DOWNLOAD_THROTTLE: Duration = Duration::from_millis(500);
+
+// application defined close code sent when the handshake fails
+const CLOSE_INCOMPATIBLE: u16 = 4000;
 
 pub async fn handler(
     app: axum::extract::State<App>,
@@ -35,48 +52,138 @@ pub async fn handler(
     })
 }
 
-struct Socket {
-    ws: WebSocket,
+struct SocketRx {
+    ws: SplitStream<WebSocket>,
 }
 
-impl Socket {
-    #[allow(unused)]
-    pub async fn recv(&mut self) -> anyhow::Result<Option<ClientMessage>> {
-        let msg = self.ws.recv().await.transpose()?;
+impl SocketRx {
+    /// Returns the next text frame from the client, or None once the
+    /// socket has closed. Control frames are skipped.
+    pub async fn recv(&mut self) -> anyhow::Result<Option<String>> {
+        loop {
+            let msg = self.ws.next().await.transpose()?;
 
-        let Some(msg) = msg else {
-            return Ok(None);
-        };
+            match msg {
+                None | Some(Message::Close(_)) => return Ok(None),
+                Some(Message::Text(json)) => return Ok(Some(json)),
+                Some(Message::Ping(_) | Message::Pong(_)) => continue,
+                Some(Message::Binary(_)) => anyhow::bail!("unexpected message type"),
+            }
+        }
+    }
+}
 
-        let Message::Text(json) = msg else {
-            anyhow::bail!("unexpected message type");
-        };
+#[derive(Clone)]
+struct SocketTx {
+    ws: Arc<Mutex<SplitSink<WebSocket, Message>>>,
+}
 
-        Ok(Some(serde_json::from_str(&json)?))
+impl SocketTx {
+    pub async fn send(&self, msg: ServerMessage) -> anyhow::Result<()> {
+        let json = serde_json::to_string(&msg)?;
+        self.ws.lock().await.send(Message::Text(json)).await?;
+        Ok(())
     }
 
-    pub async fn send(&mut self, msg: ServerMessage) -> anyhow::Result<()> {
-        let json = serde_json::to_string(&msg)?;
-        self.ws.send(Message::Text(json)).await?;
+    /// Tells the client why it is being turned away and closes the socket
+    pub async fn reject(&self, message: String) -> anyhow::Result<()> {
+        log::warn!("rejecting websocket client: {message}");
+
+        self.send(ServerMessage::Error { request: None, message: message.clone() }).await?;
+
+        let frame = CloseFrame {
+            code: CLOSE_INCOMPATIBLE,
+            reason: Cow::Owned(message),
+        };
+
+        self.ws.lock().await.send(Message::Close(Some(frame))).await?;
         Ok(())
     }
 }
 
 struct State {
-    socket: Socket,
+    socket: SocketTx,
     session: Session,
     current_track: Option<TrackId>,
 }
 
 async fn handle_socket(app: axum::extract::State<App>, ws: WebSocket, _: SocketAddr) -> anyhow::Result<()> {
-    let session = app.session().await?;
+    let (tx, rx) = ws.split();
+    let tx = SocketTx { ws: Arc::new(Mutex::new(tx)) };
+    let mut rx = SocketRx { ws: rx };
+
+    let Some(capabilities) = handshake(&mut rx, &tx).await? else {
+        return Ok(());
+    };
 
-    let mut state = State {
-        socket: Socket { ws },
-        session,
+    // the idle loop holds its mpd connection in idle for as long as the
+    // socket is open, client commands get sessions of their own
+    let state = State {
+        socket: tx.clone(),
+        session: app.session().await?,
         current_track: None,
     };
 
+    tokio::select! {
+        result = watch_events(state) => result,
+        result = watch_downloads(app.0.clone(), tx.clone()), if capabilities.download_progress => result,
+        result = handle_commands(app.0.clone(), capabilities, rx, tx) => result,
+    }
+}
+
+/// Waits for the client hello and replies with our own. Returns the
+/// capabilities in effect for the connection, or None if the client was
+/// rejected.
+async fn handshake(rx: &mut SocketRx, tx: &SocketTx) -> anyhow::Result<Option<Capabilities>> {
+    let json = match tokio::time::timeout(HELLO_TIMEOUT, rx.recv()).await {
+        Ok(json) => json?,
+        Err(_) => None,
+    };
+
+    let Some(json) = json else {
+        tx.reject("client did not say hello, please reload the page".into()).await?;
+        return Ok(None);
+    };
+
+    let (protocol, capabilities) = match negotiate(&json) {
+        Ok(negotiated) => negotiated,
+        Err(message) => {
+            tx.reject(message).await?;
+            return Ok(None);
+        }
+    };
+
+    tx.send(ServerMessage::Hello {
+        protocol,
+        server_version: env!("CARGO_PKG_VERSION").to_owned(),
+        capabilities,
+    }).await?;
+
+    Ok(Some(capabilities))
+}
+
+/// Works out the protocol version and capabilities to use from the
+/// client's hello, or why the client has to be turned away
+fn negotiate(json: &str) -> Result<(u32, Capabilities), String> {
+    let (protocol, client_capabilities) = match serde_json::from_str(json) {
+        Ok(ClientMessage::Hello { protocol, capabilities }) => (protocol, capabilities),
+        _ => return Err("expected hello as first message, please reload the page".into()),
+    };
+
+    if protocol < MIN_PROTOCOL_VERSION {
+        return Err(format!(
+            "client protocol version {protocol} is too old, server requires at least \
+            version {MIN_PROTOCOL_VERSION}, please reload the page"));
+    }
+
+    // newer clients are expected to fall back to our version
+    let protocol = protocol.min(PROTOCOL_VERSION);
+    let capabilities = Capabilities::ALL.intersect(client_capabilities);
+
+    Ok((protocol, capabilities))
+}
+
+async fn watch_events(mut state: State) -> anyhow::Result<()> {
     // send initial state to client
     send_player_status(&mut state).await?;
     send_playlist(&mut state).await?;
@@ -88,12 +195,161 @@ async fn handle_socket(app: axum::extract::State<App>, ws: WebSocket, _: SocketA
         for event in changed.events() {
             match event {
                 MpdEvent::Playlist => send_playlist(&mut state).await?,
-                MpdEvent::Player => send_player_status(&mut state).await?,
+                MpdEvent::Player | MpdEvent::Mixer => send_player_status(&mut state).await?,
             }
         }
     }
 }
 
+async fn watch_downloads(app: App, socket: SocketTx) -> anyhow::Result<()> {
+    let archive = app.archive();
+    let mut changed = archive.watch_downloads();
+
+    // last status sent to the client for each track
+    let mut sent = HashMap::<TrackId, DownloadStatus>::new();
+
+    loop {
+        changed.changed().await?;
+
+        // downloads can go quiet for longer than mpd keeps an idle
+        // connection open, so this connects afresh for each update. a
+        // failed update shouldn't take the whole socket down with it
+        let playlist = match queue_items(&app).await {
+            Ok(playlist) => playlist,
+            Err(e) => {
+                log::warn!("looking up queue for download progress: {e:?}");
+                tokio::time::sleep(DOWNLOAD_THROTTLE).await;
+                continue;
+            }
+        };
+
+        let mut current = HashMap::new();
+
+        for item in playlist.items {
+            let Some(stream_id) = metadata::parse_stream_url(&item.file) else {
+                continue;
+            };
+
+            let track = TrackId::from(item.id);
+
+            let status = match archive.download_status(stream_id) {
+                Some(status) => status,
+                None => match sent.get(&track) {
+                    // the archive forgets downloads once they have been
+                    // archived, report those as complete
+                    Some(last) if last.state == DownloadState::Downloading => DownloadStatus {
+                        state: DownloadState::Complete,
+                        downloaded_bytes: last.total_bytes.unwrap_or(last.downloaded_bytes),
+                        total_bytes: last.total_bytes,
+                        error: None,
+                    },
+                    _ => continue,
+                },
+            };
+
+            if sent.get(&track) != Some(&status) {
+                socket.send(ServerMessage::Download {
+                    track: track.clone(),
+                    download: status.clone(),
+                }).await?;
+            }
+
+            current.insert(track, status);
+        }
+
+        sent = current;
+
+        tokio::time::sleep(DOWNLOAD_THROTTLE).await;
+    }
+}
+
+async fn handle_commands(
+    app: App,
+    capabilities: Capabilities,
+    mut rx: SocketRx,
+    tx: SocketTx,
+) -> anyhow::Result<()> {
+    while let Some(json) = rx.recv().await? {
+        let message = match serde_json::from_str::<ClientMessage>(&json) {
+            Ok(message) => message,
+            Err(e) => {
+                log::warn!("malformed client message: {e}");
+                tx.send(ServerMessage::Error {
+                    request: None,
+                    message: format!("malformed message: {e}"),
+                }).await?;
+                continue;
+            }
+        };
+
+        match message {
+            ClientMessage::Hello { .. } => {
+                tx.send(ServerMessage::Error {
+                    request: None,
+                    message: "unexpected hello after handshake".into(),
+                }).await?;
+            }
+            ClientMessage::Request { id, .. } if !capabilities.commands => {
+                tx.send(ServerMessage::Error {
+                    request: Some(id),
+                    message: "commands capability was not negotiated".into(),
+                }).await?;
+            }
+            ClientMessage::Request { id, command } => {
+                // commands run alongside each other, so that a slow add
+                // doesn't hold up pausing or skipping
+                let app = app.clone();
+                let tx = tx.clone();
+
+                tokio::spawn(async move {
+                    let reply = match run_command(&app, command).await {
+                        Ok(response) => ServerMessage::Ack { request: id, response },
+                        Err(e) => {
+                            log::warn!("client command failed: {e:?}");
+                            ServerMessage::Error { request: Some(id), message: e.to_string() }
+                        }
+                    };
+
+                    if let Err(e) = tx.send(reply).await {
+                        log::warn!("sending command reply: {e}");
+                    }
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn queue_items(app: &App) -> anyhow::Result<Playlist> {
+    let mut session = app.session().await?;
+    session.mpd().playlistinfo().await
+}
+
+// mpd drops connections that sit idle, so each command gets a fresh one
+// rather than sharing one for the life of the socket
+async fn run_command(app: &App, command: Command) -> anyhow::Result<CommandResponse> {
+    let session = &mut app.session().await?;
+
+    match command {
+        Command::Play => session.mpd().play().await?,
+        Command::Pause => session.mpd().pause().await?,
+        Command::Stop => session.mpd().stop().await?,
+        Command::SkipNext => session.mpd().next().await?,
+        Command::SkipBack => session.mpd().previous().await?,
+        Command::Seek { time } => session.mpd().seekcur(Seconds(time)).await?,
+        Command::Remove { track } => session.mpd().deleteid(&track.into()).await?,
+        Command::SetVolume { volume } => session.mpd().setvol(volume.min(100)).await?,
+        Command::Add { url, entries } => {
+            let tracks = api::add(session, &url, entries.as_deref(), AddOptions::default()).await?;
+            let track = tracks[0].clone();
+            return Ok(CommandResponse::Added { track, tracks });
+        }
+    }
+
+    Ok(CommandResponse::Ok)
+}
+
 async fn send_playlist(state: &mut State) -> anyhow::Result<()> {
     let queue = api::queue(&mut state.session).await?;
     state.socket.send(ServerMessage::Queue { queue }).await?;
@@ -125,3 +381,48 @@ async fn send_player_status(state: &mut State) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use hailsplay_protocol::RequestId;
+
+    use super::*;
+
+    fn hello(protocol: u32, capabilities: Capabilities) -> String {
+        serde_json::to_string(&ClientMessage::Hello { protocol, capabilities }).unwrap()
+    }
+
+    #[test]
+    fn negotiate_current_client() {
+        let (protocol, capabilities) = negotiate(&hello(PROTOCOL_VERSION, Capabilities::ALL)).unwrap();
+        assert_eq!(protocol, PROTOCOL_VERSION);
+        assert!(capabilities.commands);
+    }
+
+    #[test]
+    fn negotiate_falls_back_to_server_version() {
+        let (protocol, _) = negotiate(&hello(PROTOCOL_VERSION + 3, Capabilities::ALL)).unwrap();
+        assert_eq!(protocol, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn negotiate_leaves_out_capabilities_client_lacks() {
+        let client = Capabilities { commands: false, ..Capabilities::ALL };
+        let (_, capabilities) = negotiate(&hello(PROTOCOL_VERSION, client)).unwrap();
+        assert!(!capabilities.commands);
+    }
+
+    #[test]
+    fn negotiate_rejects_old_client() {
+        let message = negotiate(&hello(MIN_PROTOCOL_VERSION - 1, Capabilities::ALL)).unwrap_err();
+        assert!(message.contains(&format!("at least version {MIN_PROTOCOL_VERSION}")), "{message}");
+    }
+
+    #[test]
+    fn negotiate_rejects_anything_but_hello() {
+        let request = ClientMessage::Request { id: RequestId(1), command: Command::Play };
+        assert!(negotiate(&serde_json::to_string(&request).unwrap()).is_err());
+        assert!(negotiate("{\"t\":\"hello\"}").is_err());
+        assert!(negotiate("hello").is_err());
+    }
+}
@@ -0,0 +1,201 @@
+//! Measures the loudness of media with ffmpeg's EBU R128 filter and tags
+//! files with the resulting ReplayGain info, so that MPD's
+//! `replay_gain_mode` can even out the volume of tracks from different
+//! sources.
+
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use thiserror::Error;
+
+use crate::postprocess::{self, FfmpegError};
+
+// loudness that ReplayGain 2.0 gains bring tracks to, in LUFS
+const REPLAYGAIN_REFERENCE: f64 = -18.0;
+
+// and the same for the R128 gain tags of opus files
+const R128_REFERENCE: f64 = -23.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS
+    pub integrated: f64,
+    /// True peak in dBTP
+    pub true_peak: f64,
+}
+
+impl Loudness {
+    /// ReplayGain track gain in dB
+    pub fn track_gain(&self) -> f64 {
+        REPLAYGAIN_REFERENCE - self.integrated
+    }
+
+    /// ReplayGain track peak, as a linear amplitude where 1.0 is full scale
+    pub fn track_peak(&self) -> f64 {
+        10f64.powf(self.true_peak / 20.0)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum LoudnessError {
+    #[error(transparent)]
+    Ffmpeg(#[from] FfmpegError),
+    #[error("no loudness summary in ffmpeg output")]
+    ParseSummary,
+    #[error("media is silent")]
+    Silent,
+}
+
+/// Measures the loudness of `file` and tags it in place
+pub async fn normalize(ffmpeg: &Path, file: &Path) -> Result<Loudness, LoudnessError> {
+    let loudness = analyze(ffmpeg, file).await?;
+    write_tags(ffmpeg, file, &loudness).await?;
+    Ok(loudness)
+}
+
+pub async fn analyze(ffmpeg: &Path, file: &Path) -> Result<Loudness, LoudnessError> {
+    let output = postprocess::ffmpeg(ffmpeg, |command| {
+        command
+            .arg("-i").arg(file)
+            .arg("-map").arg("0:a:0")
+            .arg("-af").arg("ebur128=peak=true:framelog=quiet")
+            .arg("-f").arg("null")
+            .arg("-");
+    }).await?;
+
+    parse_summary(&output)
+}
+
+fn parse_summary(output: &str) -> Result<Loudness, LoudnessError> {
+    lazy_static! {
+        static ref INTEGRATED: Regex = Regex::new(r"I:\s+(\S+) LUFS").unwrap();
+        static ref TRUE_PEAK: Regex = Regex::new(r"Peak:\s+(\S+) dBFS").unwrap();
+    }
+
+    let (_, summary) = output.rsplit_once("Summary:")
+        .ok_or(LoudnessError::ParseSummary)?;
+
+    let integrated = INTEGRATED.captures(summary)
+        .and_then(|captures| captures[1].parse::<f64>().ok())
+        .ok_or(LoudnessError::ParseSummary)?;
+
+    // -70 LUFS is as quiet as the filter measures, and a peak of -inf
+    // doesn't parse, both mean there's nothing to normalize
+    let true_peak = TRUE_PEAK.captures(summary)
+        .and_then(|captures| captures[1].parse::<f64>().ok())
+        .filter(|peak| peak.is_finite());
+
+    match true_peak {
+        Some(true_peak) if integrated > -70.0 => Ok(Loudness { integrated, true_peak }),
+        _ => Err(LoudnessError::Silent),
+    }
+}
+
+pub async fn write_tags(ffmpeg: &Path, file: &Path, loudness: &Loudness) -> Result<(), LoudnessError> {
+    let extension = file.extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    postprocess::rewrite(ffmpeg, file, |command| {
+        command
+            .arg("-map").arg("0")
+            .arg("-c").arg("copy")
+            .arg("-metadata").arg(format!("REPLAYGAIN_TRACK_GAIN={:.2} dB", loudness.track_gain()))
+            .arg("-metadata").arg(format!("REPLAYGAIN_TRACK_PEAK={:.6}", loudness.track_peak()));
+
+        match extension.as_str() {
+            // opus has its own gain tag, in 1/256 dB steps relative to -23 LUFS
+            "opus" => {
+                let gain = ((R128_REFERENCE - loudness.integrated) * 256.0).round() as i32;
+                command.arg("-metadata").arg(format!("R128_TRACK_GAIN={gain}"));
+            }
+            // mp4 only keeps tags it knows about unless asked to
+            "m4a" | "mp4" => {
+                command.arg("-movflags").arg("use_metadata_tags");
+            }
+            _ => {}
+        }
+    }).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // how `ffmpeg -af ebur128=peak=true:framelog=quiet` signs off for a
+    // loudly mastered pop track
+    const POP_TRACK: &str = "\
+[Parsed_ebur128_0 @ 0x5581f0e3c2c0] t: 212.6  TARGET:-23 LUFS    M:  -7.9 S:  -8.3     I:  -8.5 LUFS       LRA:   4.8 LU  FTPK:  0.9 dBFS  TPK:  1.2 dBFS
+[out#0/null @ 0x5581f0e4a100] video:0kB audio:39870kB subtitle:0kB other streams:0kB global headers:0kB muxing overhead: unknown
+size=N/A time=00:03:32.60 bitrate=N/A speed= 301x
+[Parsed_ebur128_0 @ 0x5581f0e3c2c0] Summary:
+
+  Integrated loudness:
+    I:          -8.4 LUFS
+    Threshold: -18.6 LUFS
+
+  Loudness range:
+    LRA:         4.8 LU
+    Threshold: -28.5 LUFS
+    LRA low:   -11.9 LUFS
+    LRA high:   -7.1 LUFS
+
+  True peak:
+    Peak:        1.2 dBFS
+";
+
+    // and for a few seconds of digital silence
+    const SILENCE: &str = "\
+[Parsed_ebur128_0 @ 0x55d1c8a1e380] Summary:
+
+  Integrated loudness:
+    I:         -70.0 LUFS
+    Threshold:   0.0 LUFS
+
+  Loudness range:
+    LRA:         0.0 LU
+    Threshold:   0.0 LUFS
+    LRA low:     0.0 LUFS
+    LRA high:    0.0 LUFS
+
+  True peak:
+    Peak:       -inf dBFS
+";
+
+    #[test]
+    fn parse_summary() {
+        let loudness = super::parse_summary(POP_TRACK).unwrap();
+        assert_eq!(loudness.integrated, -8.4);
+        assert_eq!(loudness.true_peak, 1.2);
+
+        // turned down to the ReplayGain reference, with a peak above full scale
+        assert!((loudness.track_gain() - -9.6).abs() < 1e-9);
+        assert!((loudness.track_peak() - 1.148154).abs() < 1e-6);
+    }
+
+    #[test]
+    fn silence_is_left_alone() {
+        assert!(matches!(super::parse_summary(SILENCE), Err(LoudnessError::Silent)));
+
+        // a peak of -inf alone is just as silent
+        let quiet = SILENCE.replace("-70.0 LUFS", "-69.0 LUFS");
+        assert!(matches!(super::parse_summary(&quiet), Err(LoudnessError::Silent)));
+    }
+
+    #[test]
+    fn no_summary() {
+        assert!(matches!(super::parse_summary(""), Err(LoudnessError::ParseSummary)));
+
+        // ffmpeg gave up before its summary. the loudness in the progress
+        // line so far mustn't be taken for the integrated loudness
+        let (progress, _) = POP_TRACK.split_once("Summary:").unwrap();
+        assert!(matches!(super::parse_summary(progress), Err(LoudnessError::ParseSummary)));
+
+        let (cut_off, _) = POP_TRACK.split_once("Integrated loudness:").unwrap();
+        assert!(matches!(super::parse_summary(cut_off), Err(LoudnessError::ParseSummary)));
+    }
+}
@@ -0,0 +1,354 @@
+//! Timed lyrics, made from the subtitles yt-dlp downloads alongside media.
+//! These are kept in the archive as LRC files next to the media, which
+//! other players pick up too.
+
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+
+use hailsplay_protocol::LyricsLine;
+use lazy_static::lazy_static;
+use regex::Regex;
+use thiserror::Error;
+
+use crate::clip::Clip;
+
+// gaps between subtitles longer than this get an empty line, so that the
+// last line doesn't linger through an instrumental break
+const MIN_GAP: f64 = 1.0;
+
+#[derive(Debug, Error)]
+pub enum LyricsError {
+    #[error("reading lyrics: {0}")]
+    Read(#[source] io::Error),
+    #[error("writing lyrics: {0}")]
+    Write(#[source] io::Error),
+    #[error("unsupported subtitle format: {0}")]
+    UnsupportedFormat(String),
+}
+
+/// Reads lyrics from an LRC file, or subtitles in WebVTT or SRT format
+pub async fn read(path: &Path) -> Result<Vec<LyricsLine>, LyricsError> {
+    let extension = path.extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    let parse = match extension.as_str() {
+        "lrc" => parse_lrc,
+        "vtt" | "srt" => parse_cues,
+        _ => return Err(LyricsError::UnsupportedFormat(extension)),
+    };
+
+    let text = tokio::fs::read_to_string(path).await
+        .map_err(LyricsError::Read)?;
+
+    Ok(parse(&text))
+}
+
+/// Converts the subtitles at `subtitles` to an LRC file at `lrc`, in the
+/// time of the clip that was downloaded. Returns whether there were any
+/// lines to write
+pub async fn convert(subtitles: &Path, lrc: &Path, clip: Clip) -> Result<bool, LyricsError> {
+    let lines = in_clip(&read(subtitles).await?, clip);
+
+    if lines.iter().all(|line| line.text.is_empty()) {
+        return Ok(false);
+    }
+
+    tokio::fs::write(lrc, to_lrc(&lines)).await
+        .map_err(LyricsError::Write)?;
+
+    Ok(true)
+}
+
+/// Lines of the original media which fall within `clip`, with times
+/// relative to its start. The line being sung as the clip starts is
+/// moved up to the start
+pub fn in_clip(lines: &[LyricsLine], clip: Clip) -> Vec<LyricsLine> {
+    lines.iter()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            let next = lines.get(index + 1).map_or(f64::INFINITY, |next| next.time);
+            let (start, _) = clip.span_in_clip(line.time, next)?;
+
+            Some(LyricsLine { time: start, text: line.text.clone() })
+        })
+        .collect()
+}
+
+fn to_lrc(lines: &[LyricsLine]) -> String {
+    let mut lrc = String::new();
+
+    for line in lines {
+        let centis = (line.time * 100.0).round() as u64;
+        let (minutes, centis) = (centis / 6000, centis % 6000);
+
+        let _ = writeln!(lrc, "[{minutes:02}:{:02}.{:02}]{}", centis / 100, centis % 100, line.text);
+    }
+
+    lrc
+}
+
+fn parse_lrc(text: &str) -> Vec<LyricsLine> {
+    lazy_static! {
+        static ref TAG: Regex = Regex::new(r"^\[(\d+):(\d+(?:\.\d+)?)\]").unwrap();
+    }
+
+    let mut lines = Vec::new();
+
+    for line in text.lines() {
+        // lines may start with several times when they're repeated,
+        // anything else in brackets is an id tag like [ar:Artist]
+        let mut rest = line.trim();
+        let mut times = Vec::new();
+
+        while let Some(captures) = TAG.captures(rest) {
+            let minutes = captures[1].parse::<f64>().unwrap_or_default();
+            let seconds = captures[2].parse::<f64>().unwrap_or_default();
+            times.push(minutes * 60.0 + seconds);
+            rest = &rest[captures[0].len()..];
+        }
+
+        for time in times {
+            lines.push(LyricsLine { time, text: rest.trim().to_owned() });
+        }
+    }
+
+    lines.sort_by(|a, b| a.time.total_cmp(&b.time));
+    lines
+}
+
+// WebVTT and SRT are near enough the same for our purposes: blocks of
+// text separated by blank lines, each with a line of timings in it
+fn parse_cues(text: &str) -> Vec<LyricsLine> {
+    let text = text.replace("\r\n", "\n");
+
+    let mut lines = Vec::<LyricsLine>::new();
+    let mut previous = Vec::<String>::new();
+    let mut previous_end = None;
+
+    for block in text.split("\n\n") {
+        let mut block_lines = block.lines();
+
+        let Some(timing) = block_lines.find(|line| line.contains("-->")) else {
+            continue;
+        };
+
+        let Some((start, end)) = parse_timing(timing) else {
+            continue;
+        };
+
+        // automatic captions flash up the last cue again for a moment
+        // before the next, these add nothing
+        if end - start < 0.05 {
+            continue;
+        }
+
+        let texts = block_lines
+            .map(clean_text)
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<_>>();
+
+        // automatic captions also scroll, repeating the last line of the
+        // previous cue above each new one
+        let new_text = texts.iter()
+            .filter(|text| !previous.contains(text))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if new_text.is_empty() {
+            continue;
+        }
+
+        if let Some(gap_start) = previous_end.filter(|end| start - end > MIN_GAP) {
+            lines.push(LyricsLine { time: gap_start, text: String::new() });
+        }
+
+        lines.push(LyricsLine { time: start, text: new_text.join(" ") });
+        previous = texts;
+        previous_end = Some(end);
+    }
+
+    lines
+}
+
+fn parse_timing(line: &str) -> Option<(f64, f64)> {
+    let (start, rest) = line.split_once("-->")?;
+    // webvtt puts cue settings after the end time
+    let end = rest.split_whitespace().next()?;
+
+    Some((parse_timestamp(start.trim())?, parse_timestamp(end)?))
+}
+
+// hh:mm:ss.ttt or mm:ss.ttt, with a comma instead of a dot in SRT
+fn parse_timestamp(timestamp: &str) -> Option<f64> {
+    timestamp.replace(',', ".")
+        .split(':')
+        .try_fold(0.0, |total, part| Some(total * 60.0 + part.parse::<f64>().ok()?))
+}
+
+fn clean_text(line: &str) -> String {
+    lazy_static! {
+        static ref TAG: Regex = Regex::new(r"<[^>]*>").unwrap();
+        // sounds rather than words, like [Music] or (applause)
+        static ref SOUND: Regex = Regex::new(r"^(\[[^\]]*\]|\([^)]*\))$").unwrap();
+    }
+
+    let text = TAG.replace_all(line, "")
+        .replace("&amp;", "&")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&nbsp;", " ");
+
+    let text = text.trim_matches(|c: char| c.is_whitespace() || c == '♪' || c == '♫');
+
+    match SOUND.is_match(text) {
+        true => String::new(),
+        false => text.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[LyricsLine]) -> Vec<(f64, &str)> {
+        lines.iter().map(|line| (line.time, line.text.as_str())).collect()
+    }
+
+    #[test]
+    fn parse_srt() {
+        let srt = "1\r\n00:00:01,000 --> 00:00:03,500\r\nFirst line\r\n\r\n\
+            2\r\n00:00:03,500 --> 00:00:05,000\r\nSecond line\r\nand more\r\n";
+
+        assert_eq!(lines(&parse_cues(srt)), [(1.0, "First line"), (3.5, "Second line and more")]);
+    }
+
+    #[test]
+    fn parse_vtt() {
+        let vtt = "\
+WEBVTT
+Kind: captions
+Language: en
+
+NOTE made by hand
+
+00:01.000 --> 00:03.000 align:start position:0%
+<c.colorE5E5E5>Hello</c><00:00:01.500><c> there</c>
+
+intro
+00:00:03.000 --> 00:00:04.000 line:90%
+Tom &amp; Jerry &lt;3
+";
+
+        assert_eq!(lines(&parse_cues(vtt)), [(1.0, "Hello there"), (3.0, "Tom & Jerry <3")]);
+    }
+
+    #[test]
+    fn parse_automatic_captions() {
+        // each cue scrolls the last one up a line, with a 10ms flash of
+        // the previous cue in between
+        let vtt = "\
+WEBVTT
+
+00:00:01.000 --> 00:00:03.000
+one two
+
+00:00:03.000 --> 00:00:03.010
+one two
+
+00:00:03.010 --> 00:00:05.000
+one two
+three four
+
+00:00:05.000 --> 00:00:07.000
+three four
+five six
+";
+
+        assert_eq!(lines(&parse_cues(vtt)), [(1.0, "one two"), (3.01, "three four"), (5.0, "five six")]);
+    }
+
+    #[test]
+    fn sounds_leave_a_gap() {
+        let vtt = "\
+WEBVTT
+
+00:00:01.000 --> 00:00:02.000
+♪ sung ♪
+
+00:00:02.000 --> 00:00:10.000
+[Music]
+
+00:00:10.000 --> 00:00:11.000
+(applause)
+sung again
+";
+
+        assert_eq!(lines(&parse_cues(vtt)), [(1.0, "sung"), (2.0, ""), (10.0, "sung again")]);
+    }
+
+    #[test]
+    fn malformed_cues_are_skipped() {
+        let vtt = "\
+WEBVTT
+
+00:00:01.000 -> 00:00:02.000
+no arrow
+
+00:00:xx.000 --> 00:00:03.000
+bad start
+
+00:00:04.000 -->
+no end
+
+00:00:05.000 --> 00:00:06.000
+fine
+";
+
+        assert_eq!(lines(&parse_cues(vtt)), [(5.0, "fine")]);
+        assert!(parse_cues("").is_empty());
+    }
+
+    #[test]
+    fn parse_lrc() {
+        let lrc = "\
+[ar:Artist]
+[ti:Title]
+[offset:+100]
+[00:01.50]First
+[00:10.00][00:30.00]Chorus
+[00:20]  Verse 
+[0a:01.00]Bad
+no tag
+[00:40.00
+[01:05.00]
+";
+
+        assert_eq!(lines(&super::parse_lrc(lrc)), [
+            (1.5, "First"),
+            (10.0, "Chorus"),
+            (20.0, "Verse"),
+            (30.0, "Chorus"),
+            (65.0, ""),
+        ]);
+    }
+
+    #[test]
+    fn in_clip() {
+        let original = [(0.0, "a"), (5.0, "b"), (10.0, "c"), (15.0, "")]
+            .map(|(time, text)| LyricsLine { time, text: text.to_owned() });
+
+        let clipped = |start, end| super::in_clip(&original, Clip::new(start, end));
+
+        assert_eq!(lines(&clipped(None, None)), lines(&original));
+        // the line being sung at the start moves up to it
+        assert_eq!(lines(&clipped(Some(7_000), Some(12_000))), [(0.0, "b"), (3.0, "c")]);
+        assert_eq!(lines(&clipped(Some(10_000), None)), [(0.0, "c"), (5.0, "")]);
+        assert_eq!(lines(&clipped(None, Some(5_000))), [(0.0, "a")]);
+        assert_eq!(lines(&clipped(Some(20_000), None)), [(0.0, "")]);
+    }
+}
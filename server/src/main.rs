@@ -1,13 +1,20 @@
 mod api;
+mod cli;
+mod clip;
 mod config;
 mod db;
+mod direct;
 mod error;
 mod frontend;
 mod fs;
 mod http;
+mod loudness;
+mod lyrics;
 mod maint;
 mod mime;
 mod mpd;
+mod postprocess;
+mod tags;
 mod tools;
 mod ytdlp;
 
@@ -16,6 +23,8 @@ use std::process::ExitCode;
 use std::sync::Arc;
 
 use api::archive::Archive;
+use api::live::LiveStreams;
+use api::metadata_cache::MetadataCache;
 use log::LevelFilter;
 use structopt::StructOpt;
 
@@ -28,6 +37,8 @@ enum Cmd {
     Server,
     #[structopt(flatten)]
     Tool(tools::Cmd),
+    #[structopt(flatten)]
+    Client(cli::Cmd),
 }
 
 
@@ -40,11 +51,11 @@ async fn main() -> ExitCode {
         .init();
 
     let cmd = Cmd::from_args();
-    let config = config::load();
 
     let result = match cmd {
-        Cmd::Server => run(config).await,
-        Cmd::Tool(cmd) => tools::run(cmd, config).await,
+        Cmd::Server => run(config::load()).await,
+        Cmd::Tool(cmd) => tools::run(cmd, config::load()).await,
+        Cmd::Client(cmd) => cli::run(cmd).await,
     };
 
     match result {
@@ -61,6 +72,8 @@ async fn run(config: Config) -> anyhow::Result<()> {
     let database = db::open(&config.storage.database).await?;
 
     let app = App::new(config, working, database);
+    app.archive().resume().await?;
+
     let router = http::routes(app.clone());
     let router = frontend::serve(router);
 
@@ -108,12 +121,22 @@ impl App {
     pub fn http(&self) -> reqwest::Client {
         self.0.http.clone()
     }
+
+    pub fn metadata_cache(&self) -> MetadataCache {
+        self.0.metadata_cache.clone()
+    }
+
+    pub fn live_streams(&self) -> LiveStreams {
+        self.0.live_streams.clone()
+    }
 }
 
 pub struct AppShared {
     pub config: Config,
     pub working: WorkingDirectory,
     pub archive: Archive,
+    pub metadata_cache: MetadataCache,
+    pub live_streams: LiveStreams,
     pub database: db::Pool,
     pub http: reqwest::Client,
 }
@@ -121,12 +144,16 @@ pub struct AppShared {
 impl App {
     pub fn new(config: Config, working: WorkingDirectory, database: db::Pool) -> Self {
         let http = reqwest::Client::new();
-        let archive = Archive::new(database.clone(), working.clone(), http.clone());
+        let archive = Archive::new(database.clone(), working.clone(), http.clone(), &config);
+        let metadata_cache = MetadataCache::new(database.clone(), config.ytdlp.clone());
+        let live_streams = LiveStreams::new(database.clone(), config.ytdlp.clone());
 
         App(Arc::new(AppShared {
             config,
             working,
             archive,
+            metadata_cache,
+            live_streams,
             database,
             http,
         }))
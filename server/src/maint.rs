@@ -1,9 +1,15 @@
+use std::collections::HashSet;
 use std::time::Duration;
 
+use chrono::Utc;
 use futures::{future, FutureExt};
 use tokio::{sync::oneshot};
 
-use crate::{App, api::{Session, metadata::{self, TrackKind}}, mpd::MpdEvent};
+use crate::{App, api::{Session, archive::MediaStreamId, metadata::{self, TrackKind}}, mpd::MpdEvent};
+
+// failed downloads are only removed for missing from the queue once
+// they've had this long to be added to it
+const FAILED_GRACE: Duration = Duration::from_secs(60);
 
 /// mpd maintenance tasks
 /// these run in the background while the app is running
@@ -27,10 +33,16 @@ pub fn start(app: App) -> MaintenaceTask {
 }
 
 async fn task(app: App) {
+    // downloads resumed at startup were in the queue when the server last
+    // ran, so they're eligible for cancellation if they've since left it
+    let mut queued_downloads = app.archive().active_downloads()
+        .into_iter()
+        .collect::<HashSet<_>>();
+
     loop {
         match app.session().await {
             Ok(mut session) => {
-                match run_session(&mut session).await {
+                match run_session(&mut session, &mut queued_downloads).await {
                     Ok(_) => {}
                     Err(e) => {
                         log::warn!("maintenance exited abnormally: {e:?}");
@@ -47,11 +59,17 @@ async fn task(app: App) {
 
 enum NoReturn {}
 
-async fn run_session(session: &mut Session) -> anyhow::Result<NoReturn> {
+async fn run_session(session: &mut Session, queued_downloads: &mut HashSet<MediaStreamId>)
+    -> anyhow::Result<NoReturn>
+{
     log::info!("starting maintenance session");
 
     clear_radio_stations_from_history(session).await?;
 
+    cancel_removed_downloads(session, queued_downloads).await?;
+    remove_dequeued_live_streams(session).await?;
+    prioritize_downloads(session).await?;
+
     loop {
         let changed = session.mpd().idle().await?;
 
@@ -59,13 +77,96 @@ async fn run_session(session: &mut Session) -> anyhow::Result<NoReturn> {
             match event {
                 MpdEvent::Player => {
                     clear_radio_stations_from_history(session).await?;
+                    prioritize_downloads(session).await?;
+                }
+                MpdEvent::Playlist => {
+                    cancel_removed_downloads(session, queued_downloads).await?;
+                    remove_dequeued_live_streams(session).await?;
+                    prioritize_downloads(session).await?;
                 }
-                MpdEvent::Playlist => {}
+                MpdEvent::Mixer => {}
             }
         }
     }
 }
 
+// cancels in-progress downloads whose queue item has been deleted.
+// downloads are added to the archive shortly before they are added to
+// the queue, so only downloads we have previously seen in the queue
+// are eligible for cancellation
+async fn cancel_removed_downloads(session: &mut Session, queued: &mut HashSet<MediaStreamId>)
+    -> anyhow::Result<()>
+{
+    let playlist = session.mpd().playlistinfo().await?;
+
+    let in_queue = playlist.items.iter()
+        .filter_map(|item| metadata::parse_stream_url(&item.file))
+        .collect::<HashSet<_>>();
+
+    let archive = session.app().archive();
+
+    for id in archive.active_downloads() {
+        if in_queue.contains(&id) {
+            queued.insert(id);
+        } else if queued.remove(&id) {
+            archive.cancel(id).await?;
+        }
+    }
+
+    // failed downloads stay around for the queue to show, but those which
+    // never made it into the queue would otherwise linger until restarted
+    let added_before = Utc::now() - chrono::Duration::from_std(FAILED_GRACE).unwrap();
+
+    for id in archive.failed_downloads(added_before) {
+        if !in_queue.contains(&id) {
+            archive.cancel(id).await?;
+        }
+    }
+
+    // forget downloads which have finished or been cancelled elsewhere
+    let active = archive.active_downloads();
+    queued.retain(|id| active.contains(id));
+
+    Ok(())
+}
+
+// live streams are kept only as long as their queue item, there's nothing
+// to archive
+async fn remove_dequeued_live_streams(session: &mut Session) -> anyhow::Result<()> {
+    let playlist = session.mpd().playlistinfo().await?;
+
+    let in_queue = playlist.items.iter()
+        .filter_map(|item| metadata::parse_live_url(&item.file))
+        .collect::<HashSet<_>>();
+
+    session.app().live_streams().remove_unqueued(&in_queue).await?;
+
+    Ok(())
+}
+
+// orders waiting downloads by how soon they'll be played: the current
+// track first, then the tracks after it, then any already played
+async fn prioritize_downloads(session: &mut Session) -> anyhow::Result<()> {
+    let status = session.mpd().status().await?;
+    let playlist = session.mpd().playlistinfo().await?;
+
+    let current = status.song_id.as_ref()
+        .and_then(|id| playlist.items.iter().position(|item| &item.id == id))
+        .unwrap_or(0);
+
+    let mut queued = playlist.items.iter()
+        .enumerate()
+        .filter_map(|(pos, item)| Some((pos, metadata::parse_stream_url(&item.file)?)))
+        .collect::<Vec<_>>();
+
+    queued.sort_by_key(|(pos, _)| (*pos < current, pos.abs_diff(current)));
+
+    session.app().archive()
+        .prioritize(queued.into_iter().map(|(_, id)| id));
+
+    Ok(())
+}
+
 // clears all radio stations from history except the current, if any
 async fn clear_radio_stations_from_history(session: &mut Session)
     -> anyhow::Result<()>
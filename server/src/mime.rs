@@ -15,6 +15,22 @@ pub fn from_extension(ext: &str) -> Mime {
     raw_from_ext(ext).parse().unwrap()
 }
 
+/// File extension for audio content types, for naming downloads
+pub fn audio_extension(mime: &Mime) -> Option<&'static str> {
+    match mime.essence_str() {
+        "audio/aac" => Some("aac"),
+        "audio/flac" | "audio/x-flac" => Some("flac"),
+        "audio/mp4" | "audio/x-m4a" => Some("m4a"),
+        "audio/mpeg" | "audio/mp3" => Some("mp3"),
+        "audio/ogg" | "audio/vorbis" => Some("ogg"),
+        "audio/opus" => Some("opus"),
+        "audio/wav" | "audio/x-wav" | "audio/wave" => Some("wav"),
+        "audio/webm" => Some("webm"),
+        "audio/x-matroska" => Some("mka"),
+        _ => None,
+    }
+}
+
 fn raw_from_ext(ext: &str) -> &'static str {
     match ext {
         "aac" => "audio/aac",
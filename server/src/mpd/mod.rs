@@ -52,6 +52,30 @@ pub struct PlaylistItem {
     pub id: Id,
     pub name: Option<String>,
     pub title: Option<String>,
+    /// Set for items which only play part of their file
+    pub range: Option<Range>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Range {
+    pub start: Seconds,
+    pub end: Option<Seconds>,
+}
+
+impl FromStr for Range {
+    type Err = anyhow::Error;
+
+    // formatted as "start-end", end is left off for open ended ranges
+    fn from_str(s: &str) -> Result<Range> {
+        let (start, end) = s.split_once('-').context("range missing -")?;
+
+        let end = match end {
+            "" => None,
+            end => Some(end.parse()?),
+        };
+
+        Ok(Range { start: start.parse()?, end })
+    }
 }
 
 #[derive(Debug)]
@@ -78,6 +102,7 @@ impl Changed {
 pub enum MpdEvent {
     Playlist,
     Player,
+    Mixer,
 }
 
 impl FromStr for MpdEvent {
@@ -87,6 +112,7 @@ impl FromStr for MpdEvent {
         match s {
             "player" => Ok(MpdEvent::Player),
             "playlist" => Ok(MpdEvent::Playlist),
+            "mixer" => Ok(MpdEvent::Mixer),
             _ => Err(()),
         }
     }
@@ -109,6 +135,7 @@ pub struct Status {
     pub elapsed: Option<Seconds>,
     pub duration: Option<Seconds>,
     pub audio_format: Option<String>,
+    pub volume: Option<i64>,
 }
 
 impl Mpd {
@@ -130,6 +157,16 @@ impl Mpd {
         resp.attributes.get("Id")
     }
 
+    pub async fn rangeid(&mut self, id: &Id, start: Seconds, end: Option<Seconds>) -> Result<()> {
+        let range = match end {
+            Some(end) => format!("{}:{}", start.0, end.0),
+            None => format!("{}:", start.0),
+        };
+
+        self.command("rangeid", &[&id.0, &range]).await??;
+        Ok(())
+    }
+
     pub async fn deleteid(&mut self, id: &Id) -> Result<()> {
         self.command("deleteid", &[&id.0]).await??;
         Ok(())
@@ -190,6 +227,16 @@ impl Mpd {
         Ok(())
     }
 
+    pub async fn seekcur(&mut self, time: Seconds) -> Result<()> {
+        self.command("seekcur", &[&time.0.to_string()]).await??;
+        Ok(())
+    }
+
+    pub async fn setvol(&mut self, volume: u8) -> Result<()> {
+        self.command("setvol", &[&volume.to_string()]).await??;
+        Ok(())
+    }
+
     pub async fn status(&mut self) -> Result<Status> {
         let resp = self.command("status", &[]).await??;
 
@@ -207,6 +254,7 @@ impl Mpd {
             elapsed: resp.attributes.get_opt("elapsed")?,
             duration: resp.attributes.get_opt("duration")?,
             audio_format: resp.attributes.get_opt("audio")?,
+            volume: resp.attributes.get_opt("volume")?,
         })
     }
 
@@ -223,6 +271,7 @@ fn parse_playlist_item(attrs: Attributes) -> Result<PlaylistItem> {
         id: attrs.get("Id")?,
         title: attrs.get_one("Title").map(str::to_owned),
         name: attrs.get_one("Name").map(str::to_owned),
+        range: attrs.get_one("Range").and_then(|range| range.parse().ok()),
     })
 }
 
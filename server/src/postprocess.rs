@@ -0,0 +1,279 @@
+//! Processing of downloads on their way into the archive: trimming
+//! silence from either end and evening out loudness, both done with
+//! ffmpeg. SponsorBlock segments are removed by yt-dlp while downloading.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::process::Command;
+
+use crate::config::{self, YtDlpOptions};
+
+/// Overrides of the processing configured globally, given when media is
+/// added and kept with its download job
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Processing {
+    pub trim_silence: Option<bool>,
+    /// SponsorBlock categories for yt-dlp to cut out, an empty list
+    /// cuts nothing
+    pub sponsorblock_remove: Option<Vec<String>>,
+}
+
+impl Processing {
+    pub fn trim_silence(&self, config: &config::PostProcess) -> bool {
+        self.trim_silence.unwrap_or(config.trim_silence)
+    }
+
+    /// SponsorBlock categories to cut out of media downloaded by yt-dlp
+    /// with `options`, if any
+    pub fn sponsorblock_remove<'a>(&'a self, options: &'a YtDlpOptions) -> Option<&'a [String]> {
+        self.sponsorblock_remove.as_ref()
+            .or(options.sponsorblock_remove.as_ref())
+            .filter(|categories| !categories.is_empty())
+            .map(Vec::as_slice)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum FfmpegError {
+    #[error("spawning ffmpeg: {0}")]
+    Spawn(#[source] io::Error),
+    #[error("ffmpeg failed: {0}")]
+    CommandError(String),
+    #[error("replacing processed file: {0}")]
+    Replace(#[source] io::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum TrimError {
+    #[error(transparent)]
+    Ffmpeg(#[from] FfmpegError),
+    #[error("no duration in ffmpeg output")]
+    ParseDuration,
+}
+
+/// Runs ffmpeg with the given arguments, returning what it logged
+pub async fn ffmpeg(ffmpeg: &Path, args: impl FnOnce(&mut Command)) -> Result<String, FfmpegError> {
+    let mut command = Command::new(ffmpeg);
+    command.arg("-hide_banner").arg("-nostats");
+    args(&mut command);
+
+    let output = command
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(FfmpegError::Spawn)?;
+
+    // everything of interest goes to stderr
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+
+    if !output.status.success() {
+        let last_line = stderr.lines()
+            .rfind(|line| !line.trim().is_empty())
+            .unwrap_or_default()
+            .trim()
+            .to_owned();
+
+        return Err(FfmpegError::CommandError(last_line));
+    }
+
+    Ok(stderr)
+}
+
+// ffmpeg can't edit files in place, so this writes a processed copy of the
+// file alongside it and moves it over the original. anything still reading
+// the original keeps reading the old copy until it's done
+pub async fn rewrite(ffmpeg_path: &Path, file: &Path, args: impl FnOnce(&mut Command))
+    -> Result<(), FfmpegError>
+{
+    let output = scratch_path(file);
+
+    let result = ffmpeg(ffmpeg_path, |command| {
+        command.arg("-y").arg("-i").arg(file);
+        args(command);
+        command.arg(&output);
+    }).await;
+
+    let result = match result {
+        Ok(_) => tokio::fs::rename(&output, file).await.map_err(FfmpegError::Replace),
+        Err(e) => Err(e),
+    };
+
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&output).await;
+    }
+
+    result
+}
+
+// keeps the extension, ffmpeg goes by it to pick the output format
+fn scratch_path(file: &Path) -> PathBuf {
+    let name = file.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    file.with_file_name(format!(".processing.{name}"))
+}
+
+/// Cuts silence longer than half a second from the start and end of
+/// `file`. Returns the span that was kept, in seconds, if anything was cut
+pub async fn trim_silence(config: &config::PostProcess, file: &Path) -> Result<Option<(f64, f64)>, TrimError> {
+    // silence in the middle is left alone, so this finds the silences
+    // first rather than using the silenceremove filter, which would need
+    // the whole track in memory to work backwards from the end
+    let output = ffmpeg(&config.ffmpeg, |command| {
+        command
+            .arg("-i").arg(file)
+            .arg("-map").arg("0:a:0")
+            .arg("-af").arg(format!("silencedetect=noise={}dB:duration=0.5", config.silence_threshold))
+            .arg("-f").arg("null")
+            .arg("-");
+    }).await?;
+
+    let Some((start, end)) = audible_span(&output)? else {
+        return Ok(None);
+    };
+
+    log::info!("trimming silence from {}: keeping {start:.2}s to {end:.2}s", file.display());
+
+    // copying rather than re-encoding cuts at the nearest packet, which is
+    // close enough for silence
+    rewrite(&config.ffmpeg, file, |command| {
+        command
+            .arg("-ss").arg(start.to_string())
+            .arg("-to").arg(end.to_string())
+            .arg("-map").arg("0")
+            .arg("-c").arg("copy");
+    }).await?;
+
+    Ok(Some((start, end)))
+}
+
+// the part of the media between leading and trailing silence, None if
+// there's none to trim
+fn audible_span(output: &str) -> Result<Option<(f64, f64)>, TrimError> {
+    lazy_static! {
+        static ref DURATION: Regex = Regex::new(r"Duration: (\d+):(\d+):(\d+(?:\.\d+)?)").unwrap();
+        static ref SILENCE_START: Regex = Regex::new(r"silence_start: (-?[\d.]+)").unwrap();
+        static ref SILENCE_END: Regex = Regex::new(r"silence_end: (-?[\d.]+)").unwrap();
+    }
+
+    let duration = DURATION.captures(output)
+        .and_then(|captures| {
+            let hours = captures[1].parse::<f64>().ok()?;
+            let minutes = captures[2].parse::<f64>().ok()?;
+            let seconds = captures[3].parse::<f64>().ok()?;
+            Some(hours * 3600.0 + minutes * 60.0 + seconds)
+        })
+        .ok_or(TrimError::ParseDuration)?;
+
+    let parse = |regex: &Regex| -> Vec<f64> {
+        regex.captures_iter(output)
+            .filter_map(|captures| captures[1].parse().ok())
+            .collect()
+    };
+
+    let starts = parse(&SILENCE_START);
+    let ends = parse(&SILENCE_END);
+
+    // some ffmpeg versions leave out the end of silence running to the end
+    // of the file, others report it at the end of the file
+    const SLACK: f64 = 0.05;
+
+    let start = match (starts.first(), ends.first()) {
+        (Some(&start), Some(&end)) if start <= SLACK => end,
+        _ => 0.0,
+    };
+
+    let end = match starts.last() {
+        Some(&last_start) if ends.len() < starts.len() => last_start,
+        Some(&last_start) if ends.last().is_some_and(|&end| end >= duration - SLACK) => last_start,
+        _ => duration,
+    };
+
+    if end <= start || (start == 0.0 && end == duration) {
+        return Ok(None);
+    }
+
+    Ok(Some((start, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `ffmpeg -i media.opus -map 0:a:0 -af silencedetect=noise=-50dB:duration=0.5 -f null -`
+    // on a track with a count in, a pause halfway and a fade out
+    const COUNT_IN_AND_FADE: &str = "\
+Input #0, ogg, from 'media.opus':
+  Duration: 00:03:05.00, start: 0.007500, bitrate: 117 kb/s
+  Stream #0:0(eng): Audio: opus, 48000 Hz, stereo, fltp
+Stream mapping:
+  Stream #0:0 -> #0:0 (opus (native) -> pcm_s16le (native))
+Press [q] to stop, [?] for help
+Output #0, null, to 'pipe:':
+  Stream #0:0(eng): Audio: pcm_s16le, 48000 Hz, stereo, s16, 1536 kb/s
+[silencedetect @ 0x55d0c2b0e940] silence_start: 0
+[silencedetect @ 0x55d0c2b0e940] silence_end: 2.5 | silence_duration: 2.5
+[silencedetect @ 0x55d0c2b0e940] silence_start: 92.113
+[silencedetect @ 0x55d0c2b0e940] silence_end: 92.871 | silence_duration: 0.758
+[silencedetect @ 0x55d0c2b0e940] silence_start: 180.25
+size=N/A time=00:03:05.00 bitrate=N/A speed= 412x
+";
+
+    #[test]
+    fn audible_span() {
+        assert_eq!(super::audible_span(COUNT_IN_AND_FADE).unwrap(), Some((2.5, 180.25)));
+
+        // newer ffmpeg also ends the silence running to the end of the file
+        let ended = COUNT_IN_AND_FADE.replace(
+            "size=N/A",
+            "[silencedetect @ 0x55d0c2b0e940] silence_end: 185 | silence_duration: 4.75\nsize=N/A");
+        assert_eq!(super::audible_span(&ended).unwrap(), Some((2.5, 180.25)));
+    }
+
+    #[test]
+    fn audible_span_of_long_media() {
+        let output = "\
+  Duration: 01:00:00.50, start: 0.000000, bitrate: 128 kb/s
+[silencedetect @ 0x5613] silence_start: -0.0135
+[silencedetect @ 0x5613] silence_end: 1.2 | silence_duration: 1.2135
+";
+        assert_eq!(super::audible_span(output).unwrap(), Some((1.2, 3600.5)));
+    }
+
+    #[test]
+    fn nothing_to_trim() {
+        let pause_only = "\
+  Duration: 00:03:05.00, start: 0.007500, bitrate: 117 kb/s
+[silencedetect @ 0x55d0c2b0e940] silence_start: 92.113
+[silencedetect @ 0x55d0c2b0e940] silence_end: 92.871 | silence_duration: 0.758
+";
+        assert_eq!(super::audible_span(pause_only).unwrap(), None);
+
+        let (no_silence, _) = COUNT_IN_AND_FADE.split_once("[silencedetect").unwrap();
+        assert_eq!(super::audible_span(no_silence).unwrap(), None);
+
+        // all of it is silence, which is best left for someone to look at
+        let silent = "\
+  Duration: 00:00:10.00, start: 0.000000, bitrate: 2 kb/s
+[silencedetect @ 0x55d0c2b0e940] silence_start: 0
+";
+        assert_eq!(super::audible_span(silent).unwrap(), None);
+    }
+
+    #[test]
+    fn audible_span_without_duration() {
+        let live = COUNT_IN_AND_FADE.replace("Duration: 00:03:05.00", "Duration: N/A");
+        assert!(matches!(super::audible_span(&live), Err(TrimError::ParseDuration)));
+
+        let missing = "media.opus: No such file or directory\n";
+        assert!(matches!(super::audible_span(missing), Err(TrimError::ParseDuration)));
+    }
+}
@@ -0,0 +1,401 @@
+//! Reads the title/artist style tags embedded in audio files: ID3v2 for
+//! mp3, and vorbis comments for ogg (vorbis and opus) and flac.
+//!
+//! Only the start of the file is needed since that's where these formats
+//! keep their tags. Tags which run past the end of what's given are
+//! skipped rather than treated as an error, so a file's first few hundred
+//! KiB are plenty even when a large cover image comes first.
+
+#[derive(Debug, Default)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub genre: Option<String>,
+}
+
+pub fn read(head: &[u8]) -> Tags {
+    let mut tags = Tags::default();
+
+    if head.starts_with(b"ID3") {
+        read_id3v2(head, &mut tags);
+    } else if head.starts_with(b"OggS") {
+        read_ogg(head, &mut tags);
+    } else if let Some(blocks) = head.strip_prefix(b"fLaC") {
+        read_flac(blocks, &mut tags);
+    }
+
+    tags
+}
+
+fn read_id3v2(head: &[u8], tags: &mut Tags) -> Option<()> {
+    let version = *head.get(3)?;
+    let flags = *head.get(5)?;
+    let size = syncsafe(head.get(6..10)?)?;
+
+    // v2.2 has three character frame ids and is long obsolete
+    if version != 3 && version != 4 {
+        return None;
+    }
+
+    let end = 10 + size;
+    let mut pos = 10;
+
+    if flags & 0x40 != 0 {
+        // extended header, v3 doesn't count the size field in its size
+        let size = head.get(pos..pos + 4)?;
+        pos += match version {
+            3 => 4 + be_u32(size)?,
+            _ => syncsafe(size)?,
+        };
+    }
+
+    while pos + 10 <= end {
+        let header = head.get(pos..pos + 10)?;
+        let id = &header[0..4];
+
+        if id[0] == 0 {
+            // reached the padding
+            break;
+        }
+
+        let size = match version {
+            3 => be_u32(&header[4..8])?,
+            _ => syncsafe(&header[4..8])?,
+        };
+
+        let body = head.get(pos + 10..pos + 10 + size)?;
+        pos += 10 + size;
+
+        let field = match id {
+            b"TIT2" => &mut tags.title,
+            b"TPE1" => &mut tags.artist,
+            b"TCON" => &mut tags.genre,
+            _ => continue,
+        };
+
+        if field.is_none() {
+            *field = id3_text(body);
+        }
+    }
+
+    Some(())
+}
+
+fn id3_text(body: &[u8]) -> Option<String> {
+    let (&encoding, text) = body.split_first()?;
+
+    let text = match encoding {
+        0 => text.iter().map(|&byte| char::from(byte)).collect(),
+        1 => utf16(text, None)?,
+        2 => utf16(text, Some(false))?,
+        3 => String::from_utf8_lossy(text).into_owned(),
+        _ => return None,
+    };
+
+    // frames may hold several null separated values, the first will do
+    let text = text.split('\0').next().unwrap_or_default().trim();
+    (!text.is_empty()).then(|| text.to_owned())
+}
+
+// little_endian is None when the text starts with a byte order mark
+fn utf16(text: &[u8], little_endian: Option<bool>) -> Option<String> {
+    let (little_endian, text) = match little_endian {
+        Some(little_endian) => (little_endian, text),
+        None => match text.get(0..2)? {
+            [0xff, 0xfe] => (true, &text[2..]),
+            [0xfe, 0xff] => (false, &text[2..]),
+            _ => return None,
+        },
+    };
+
+    let units = text.chunks_exact(2).map(|pair| match little_endian {
+        true => u16::from_le_bytes([pair[0], pair[1]]),
+        false => u16::from_be_bytes([pair[0], pair[1]]),
+    });
+
+    Some(char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect())
+}
+
+fn read_ogg(head: &[u8], tags: &mut Tags) -> Option<()> {
+    // the comment header is the second packet of the stream, which may
+    // be split across several pages
+    let mut packets = Vec::new();
+    let mut packet = Vec::new();
+    let mut pos = 0;
+
+    while packets.len() < 2 {
+        let page = head.get(pos..)?;
+
+        if !page.starts_with(b"OggS") {
+            return None;
+        }
+
+        let segment_count = usize::from(*page.get(26)?);
+        let lacing = page.get(27..27 + segment_count)?;
+        let mut offset = 27 + segment_count;
+
+        for &length in lacing {
+            let length = usize::from(length);
+            packet.extend_from_slice(page.get(offset..offset + length)?);
+            offset += length;
+
+            // a packet continues into the next segment when it fills this one
+            if length < 255 {
+                packets.push(std::mem::take(&mut packet));
+            }
+        }
+
+        pos += offset;
+    }
+
+    let comments = &packets[1];
+
+    let comments = comments.strip_prefix(b"\x03vorbis")
+        .or_else(|| comments.strip_prefix(b"OpusTags"))?;
+
+    read_vorbis_comments(comments, tags)
+}
+
+fn read_flac(mut blocks: &[u8], tags: &mut Tags) -> Option<()> {
+    loop {
+        let header = blocks.get(0..4)?;
+        let last = header[0] & 0x80 != 0;
+        let kind = header[0] & 0x7f;
+        let size = be_u32(&[0, header[1], header[2], header[3]])?;
+
+        const VORBIS_COMMENT: u8 = 4;
+
+        if kind == VORBIS_COMMENT {
+            return read_vorbis_comments(blocks.get(4..4 + size)?, tags);
+        }
+
+        if last {
+            return None;
+        }
+
+        blocks = blocks.get(4 + size..)?;
+    }
+}
+
+fn read_vorbis_comments(data: &[u8], tags: &mut Tags) -> Option<()> {
+    let vendor_length = le_u32(data.get(0..4)?)?;
+    let mut pos = 4 + vendor_length;
+
+    let count = le_u32(data.get(pos..pos + 4)?)?;
+    pos += 4;
+
+    for _ in 0..count {
+        let length = le_u32(data.get(pos..pos + 4)?)?;
+        let comment = data.get(pos + 4..pos + 4 + length)?;
+        pos += 4 + length;
+
+        let comment = String::from_utf8_lossy(comment);
+
+        let Some((key, value)) = comment.split_once('=') else {
+            continue;
+        };
+
+        let field = match key.to_ascii_uppercase().as_str() {
+            "TITLE" => &mut tags.title,
+            "ARTIST" => &mut tags.artist,
+            "GENRE" => &mut tags.genre,
+            _ => continue,
+        };
+
+        let value = value.trim();
+
+        if field.is_none() && !value.is_empty() {
+            *field = Some(value.to_owned());
+        }
+    }
+
+    Some(())
+}
+
+// id3v2 sizes use 7 bits per byte
+fn syncsafe(bytes: &[u8]) -> Option<usize> {
+    let bytes: [u8; 4] = bytes.try_into().ok()?;
+    let size = bytes.iter().fold(0u32, |size, &byte| (size << 7) | u32::from(byte & 0x7f));
+    usize::try_from(size).ok()
+}
+
+fn be_u32(bytes: &[u8]) -> Option<usize> {
+    usize::try_from(u32::from_be_bytes(bytes.try_into().ok()?)).ok()
+}
+
+fn le_u32(bytes: &[u8]) -> Option<usize> {
+    usize::try_from(u32::from_le_bytes(bytes.try_into().ok()?)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id3(version: u8, frames: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+
+        for (id, text) in frames {
+            let size = u32::try_from(text.len()).unwrap();
+            body.extend_from_slice(*id);
+            body.extend_from_slice(&match version {
+                3 => size.to_be_bytes(),
+                _ => syncsafe_bytes(size),
+            });
+            body.extend_from_slice(&[0, 0]);
+            body.extend_from_slice(text);
+        }
+
+        // some padding, as taggers leave room to grow
+        body.extend_from_slice(&[0; 16]);
+
+        let mut tag = vec![b'I', b'D', b'3', version, 0, 0];
+        tag.extend_from_slice(&syncsafe_bytes(u32::try_from(body.len()).unwrap()));
+        tag.extend_from_slice(&body);
+        tag
+    }
+
+    fn syncsafe_bytes(size: u32) -> [u8; 4] {
+        [(size >> 21) as u8 & 0x7f, (size >> 14) as u8 & 0x7f, (size >> 7) as u8 & 0x7f, size as u8 & 0x7f]
+    }
+
+    fn vorbis_comments(comments: &[&str]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&6u32.to_le_bytes());
+        data.extend_from_slice(b"vendor");
+        data.extend_from_slice(&u32::try_from(comments.len()).unwrap().to_le_bytes());
+
+        for comment in comments {
+            data.extend_from_slice(&u32::try_from(comment.len()).unwrap().to_le_bytes());
+            data.extend_from_slice(comment.as_bytes());
+        }
+
+        data
+    }
+
+    // one page per packet, each packet in as many segments as it takes
+    fn ogg(packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut stream = Vec::new();
+
+        for packet in packets {
+            let mut lacing = vec![255; packet.len() / 255];
+            lacing.push((packet.len() % 255) as u8);
+
+            stream.extend_from_slice(b"OggS");
+            stream.extend_from_slice(&[0; 22]);
+            stream.push(u8::try_from(lacing.len()).unwrap());
+            stream.extend_from_slice(&lacing);
+            stream.extend_from_slice(packet);
+        }
+
+        stream
+    }
+
+    fn flac(blocks: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let mut stream = b"fLaC".to_vec();
+
+        for (index, (kind, data)) in blocks.iter().enumerate() {
+            let last = if index + 1 == blocks.len() { 0x80 } else { 0 };
+            let size = u32::try_from(data.len()).unwrap().to_be_bytes();
+            stream.extend_from_slice(&[kind | last, size[1], size[2], size[3]]);
+            stream.extend_from_slice(data);
+        }
+
+        stream
+    }
+
+    fn utf16le(text: &str) -> Vec<u8> {
+        let mut bytes = vec![1, 0xff, 0xfe];
+        bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        bytes
+    }
+
+    fn opus_tags(comments: &[&str]) -> Vec<u8> {
+        [b"OpusTags".as_slice(), &vorbis_comments(comments)].concat()
+    }
+
+    // title, artist and genre, for comparing all at once
+    fn fields(tags: &Tags) -> [Option<&str>; 3] {
+        [tags.title.as_deref(), tags.artist.as_deref(), tags.genre.as_deref()]
+    }
+
+    #[test]
+    fn read_id3() {
+        let v3 = id3(3, &[(b"TIT2", b"\0Title"), (b"TPE1", &utf16le("Artist")), (b"TCON", b"\x03Genre\0Other")]);
+        assert_eq!(fields(&read(&v3)), [Some("Title"), Some("Artist"), Some("Genre")]);
+
+        let v4 = id3(4, &[(b"TXXX", b"\x03ignored"), (b"TIT2", "\x03Tïtle".as_bytes())]);
+        assert_eq!(fields(&read(&v4)), [Some("Tïtle"), None, None]);
+
+        // too old to bother with
+        assert_eq!(fields(&read(&id3(2, &[(b"TIT2", b"\0Title")]))), [None; 3]);
+    }
+
+    #[test]
+    fn read_bad_id3_frames() {
+        let blank = id3(3, &[(b"TIT2", b"\0  "), (b"TPE1", b"")]);
+        assert_eq!(fields(&read(&blank)), [None; 3]);
+
+        let unknown_encoding = id3(3, &[(b"TIT2", b"\x07Title")]);
+        assert_eq!(read(&unknown_encoding).title, None);
+
+        let bad_bom = id3(3, &[(b"TIT2", b"\x01\x00\x00T\x00")]);
+        assert_eq!(read(&bad_bom).title, None);
+
+        // a frame claiming more than the whole tag, in place of the padding
+        let mut oversized = id3(3, &[(b"TIT2", b"\0Title")]);
+        oversized[26..36].copy_from_slice(b"TPE1\x7f\xff\xff\xff\0\0");
+        assert_eq!(fields(&read(&oversized)), [Some("Title"), None, None]);
+    }
+
+    #[test]
+    fn read_truncated_id3() {
+        let tag = id3(3, &[(b"TIT2", b"\0Title"), (b"TPE1", b"\0Artist")]);
+        assert_eq!(fields(&read(&tag[..30])), [Some("Title"), None, None]);
+        assert_eq!(fields(&read(b"ID3\x03\0")), [None; 3]);
+    }
+
+    #[test]
+    fn read_ogg() {
+        let opus = ogg(&[b"OpusHead".to_vec(), opus_tags(&["title=Title", "ARTIST=Artist", "genre=", "GENRE=Genre"])]);
+        assert_eq!(fields(&read(&opus)), [Some("Title"), Some("Artist"), Some("Genre")]);
+
+        // a comment packet spanning several segments
+        let long_title = format!("TITLE={}", "a".repeat(300));
+        let vorbis = ogg(&[b"\x01vorbis".to_vec(), [b"\x03vorbis".as_slice(), &vorbis_comments(&[&long_title])].concat()]);
+        assert_eq!(read(&vorbis).title, Some("a".repeat(300)));
+    }
+
+    #[test]
+    fn read_bad_ogg() {
+        assert_eq!(fields(&read(&ogg(&[b"OpusHead".to_vec()]))), [None; 3]);
+
+        let truncated = ogg(&[b"OpusHead".to_vec(), opus_tags(&["TITLE=Title"])]);
+        assert_eq!(fields(&read(&truncated[..60])), [None; 3]);
+
+        let speex = ogg(&[b"Speex".to_vec(), vorbis_comments(&["TITLE=Title"])]);
+        assert_eq!(fields(&read(&speex)), [None; 3]);
+    }
+
+    #[test]
+    fn read_flac() {
+        let flac_file = flac(&[(0, vec![0; 34]), (1, vec![0; 100]), (4, vorbis_comments(&["ARTIST=Artist", "no equals sign"]))]);
+        assert_eq!(fields(&read(&flac_file)), [None, Some("Artist"), None]);
+
+        assert_eq!(fields(&read(&flac(&[(0, vec![0; 34])]))), [None; 3]);
+
+        let truncated = flac(&[(0, vec![0; 34]), (4, vorbis_comments(&["TITLE=Title"]))]);
+        assert_eq!(fields(&read(&truncated[..50])), [None; 3]);
+
+        // a comment count that would have us allocate forever
+        let huge_count = [6u32.to_le_bytes().as_slice(), b"vendor", &u32::MAX.to_le_bytes()].concat();
+        assert_eq!(fields(&read(&flac(&[(4, huge_count)]))), [None; 3]);
+    }
+
+    #[test]
+    fn read_other_files() {
+        assert_eq!(fields(&read(b"")), [None; 3]);
+        assert_eq!(fields(&read(b"<!DOCTYPE html>")), [None; 3]);
+    }
+}
@@ -7,15 +7,19 @@ use crate::api::asset;
 use crate::config::Config;
 use crate::db;
 use crate::db::radio::Station;
+use crate::loudness;
 
 #[derive(StructOpt)]
 pub enum Cmd {
     AddStation(AddStationOpt),
+    /// Measure the loudness of archived media and tag it with ReplayGain info
+    NormalizeArchive(NormalizeArchiveOpt),
 }
 
 pub async fn run(cmd: Cmd, config: Config) -> anyhow::Result<()> {
     match cmd {
         Cmd::AddStation(opt) => add_station(opt, config).await,
+        Cmd::NormalizeArchive(opt) => normalize_archive(opt, config).await,
     }
 }
 
@@ -46,3 +50,49 @@ async fn add_station(opt: AddStationOpt, config: Config) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[derive(StructOpt)]
+pub struct NormalizeArchiveOpt {
+    /// Measure media again even if it already has been
+    #[structopt(long)]
+    all: bool,
+}
+
+async fn normalize_archive(opt: NormalizeArchiveOpt, config: Config) -> anyhow::Result<()> {
+    let database = db::open(&config.storage.database).await?;
+
+    let records = database.with(|conn| {
+        db::archive::load_for_loudness(conn, opt.all)
+    }).await?;
+
+    log::info!("Measuring loudness of {} archived files", records.len());
+
+    let mut failed = 0;
+
+    for (id, record) in records {
+        let path = config.storage.archive.join(&record.filename);
+
+        // one bad file shouldn't hold up the rest
+        let loudness = match loudness::normalize(&config.postprocess.ffmpeg, &path).await {
+            Ok(loudness) => loudness,
+            Err(e) => {
+                log::warn!("measuring loudness of {}: {e}", path.display());
+                failed += 1;
+                continue;
+            }
+        };
+
+        log::info!("{}: {:.1} LUFS, track gain {:.2} dB",
+            record.filename, loudness.integrated, loudness.track_gain());
+
+        database.with(|conn| {
+            db::archive::set_loudness(conn, id, loudness.track_gain(), loudness.track_peak())
+        }).await?;
+    }
+
+    if failed > 0 {
+        anyhow::bail!("failed to measure {failed} files");
+    }
+
+    Ok(())
+}
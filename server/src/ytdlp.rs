@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::io;
 use std::path::Path;
 use std::process::Stdio;
@@ -6,19 +7,25 @@ use std::task::Poll;
 
 use derive_more::From;
 use futures::future::Shared;
-use futures::{FutureExt, future};
+use futures::{Future, FutureExt, future};
+use hailsplay_protocol::SearchSite;
 use regex::Regex;
 use lazy_static::lazy_static;
-use tokio::process::{Command, Child, ChildStdout};
+use tokio::process::{Command, Child, ChildStderr, ChildStdout};
 use tokio::io::{AsyncRead, AsyncReadExt, BufReader, AsyncBufReadExt};
 use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 use url::Url;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::fs::{SharedDir, SharedFile};
+use crate::clip::Clip;
+use crate::config::{self, YtDlpOptions};
+use crate::fs::{OwnedFile, SharedDir, SharedFile};
+use crate::postprocess::Processing;
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
 pub struct Metadata {
     pub title: Option<String>,
     #[serde(rename = "fulltitle")]
@@ -30,27 +37,144 @@ pub struct Metadata {
     pub webpage_url: Option<Url>,
     pub genre: Option<String>,
     pub thumbnail: Option<Url>,
+    pub is_live: Option<bool>,
+    pub chapters: Option<Vec<Chapter>>,
+    // not known until yt-dlp has picked a format to download:
+    #[serde(default)]
     pub ext: String,
+    #[serde(default)]
     pub audio_ext: String,
+    #[serde(default)]
     pub video_ext: String,
 }
 
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Chapter {
+    pub start_time: f64,
+    pub end_time: f64,
+    pub title: Option<String>,
+}
+
+/// Result of looking up a url: either a single item or a playlist
+/// (album, channel, set...) of entries which can each be downloaded
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Info {
+    Single(Box<Metadata>),
+    Playlist(Playlist),
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Playlist {
+    pub title: Option<String>,
+    pub uploader: Option<String>,
+    pub webpage_url: Option<Url>,
+    #[serde(default)]
+    pub thumbnails: Vec<Thumbnail>,
+    #[serde(default)]
+    pub entries: Vec<PlaylistEntry>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PlaylistEntry {
+    // flat entries of some extractors only carry an id here rather than
+    // a url, so this is parsed lazily by PlaylistEntry::url
+    pub url: Option<String>,
+    pub title: Option<String>,
+    pub uploader: Option<String>,
+    pub channel: Option<String>,
+    pub duration: Option<f64>,
+    pub live_status: Option<String>,
+    #[serde(default)]
+    pub thumbnails: Vec<Thumbnail>,
+}
+
+impl PlaylistEntry {
+    /// Returns None if this entry can't be downloaded on its own
+    pub fn url(&self) -> Option<Url> {
+        Url::parse(self.url.as_deref()?).ok()
+    }
+
+    /// What we know about this entry before downloading it
+    pub fn metadata(&self) -> Metadata {
+        Metadata {
+            title: self.title.clone(),
+            full_title: self.title.clone(),
+            uploader: self.uploader.clone().or_else(|| self.channel.clone()),
+            duration: self.duration,
+            webpage_url: self.url(),
+            thumbnail: best_thumbnail(&self.thumbnails).cloned(),
+            is_live: self.live_status.as_ref().map(|status| status == "is_live"),
+            ..Metadata::default()
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Thumbnail {
+    pub url: Url,
+}
+
+/// yt-dlp orders thumbnails from worst to best
+pub fn best_thumbnail(thumbnails: &[Thumbnail]) -> Option<&Url> {
+    thumbnails.last().map(|thumbnail| &thumbnail.url)
+}
+
 #[derive(Debug, Error)]
 pub enum FetchMetadataError {
     #[error("spawning yt-dlp command: {0}")]
     Spawn(std::io::Error),
-    #[error("command failed: stderr output: {0}")]
-    CommandError(String),
+    #[error("yt-dlp failed: {0}")]
+    CommandError(#[source] YtDlpError),
     #[error("parsing metadata: {0}")]
     ParseMetadata(serde_json::Error),
 }
 
-pub async fn fetch_metadata(url: &Url) -> Result<Metadata, FetchMetadataError> {
-    const MAX_READ_SIZE: u64 = 512 * 1024; // 512 KiB
+/// Builds a yt-dlp command with the options common to every invocation
+fn command(config: &config::YtDlp, options: &YtDlpOptions) -> Command {
+    let mut command = Command::new(&config.binary);
 
-    let mut process = tokio::process::Command::new("yt-dlp")
-        .arg("--dump-json")
-        .arg(url.to_string())
+    if let Some(cookies) = &options.cookies {
+        command.arg("--cookies").arg(cookies);
+    }
+
+    if let Some(proxy) = &options.proxy {
+        command.arg("--proxy").arg(proxy);
+    }
+
+    command.args(&options.extra_args);
+    command
+}
+
+pub async fn fetch_metadata(config: &config::YtDlp, url: &Url) -> Result<Info, FetchMetadataError> {
+    dump_info(config, &config.options_for(url), url.as_str()).await
+}
+
+/// Searches `site` for `query`, the results come back as a playlist
+pub async fn search(config: &config::YtDlp, site: SearchSite, query: &str)
+    -> Result<Info, FetchMetadataError>
+{
+    let (extractor, home) = match site {
+        SearchSite::Youtube => ("ytsearch", "https://www.youtube.com/"),
+        SearchSite::Soundcloud => ("scsearch", "https://soundcloud.com/"),
+    };
+
+    // site overrides apply to searches as they do to urls on that site
+    let options = config.options_for(&Url::parse(home).unwrap());
+    let target = format!("{extractor}{}:{query}", config.search_results);
+
+    dump_info(config, &options, &target).await
+}
+
+async fn dump_info(config: &config::YtDlp, options: &YtDlpOptions, target: &str)
+    -> Result<Info, FetchMetadataError>
+{
+    const MAX_READ_SIZE: u64 = 4 * 1024 * 1024; // 4 MiB, playlists can be long
+
+    let mut process = command(config, options)
+        .arg("--flat-playlist")
+        .arg("--dump-single-json")
+        .arg(target)
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .kill_on_drop(true)
@@ -74,14 +198,141 @@ pub async fn fetch_metadata(url: &Url) -> Result<Metadata, FetchMetadataError> {
     match status {
         Ok(status) if status.success() => {}
         _ => {
-            return Err(FetchMetadataError::CommandError(stderr));
+            return Err(FetchMetadataError::CommandError(YtDlpError::from_stderr(&stderr)));
         }
     }
 
-    let metadata = serde_json::from_str::<Metadata>(&stdout)
-        .map_err(FetchMetadataError::ParseMetadata)?;
+    parse_info(&stdout)
+        .map_err(FetchMetadataError::ParseMetadata)
+}
 
-    Ok(metadata)
+#[derive(Debug, Error)]
+pub enum ResolveUrlError {
+    #[error("spawning yt-dlp command: {0}")]
+    Spawn(std::io::Error),
+    #[error("yt-dlp failed: {0}")]
+    CommandError(#[source] YtDlpError),
+    #[error("parsing stream url: {0}")]
+    ParseUrl(url::ParseError),
+}
+
+/// Asks yt-dlp for a url the media can be streamed from directly. These
+/// are usually signed and expire after a few hours
+pub async fn resolve_stream_url(config: &config::YtDlp, url: &Url) -> Result<Url, ResolveUrlError> {
+    let options = config.options_for(url);
+    // download formats are often picked for a container or codec which
+    // live streams aren't offered in
+    let format = options.live_format.as_deref().unwrap_or("bestaudio/best");
+
+    let output = command(config, &options)
+        .arg("--format").arg(format)
+        .arg("--get-url")
+        .arg(url.to_string())
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(ResolveUrlError::Spawn)?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(ResolveUrlError::CommandError(YtDlpError::from_stderr(&stderr)));
+    }
+
+    // formats which merge separate streams print a url for each, the
+    // player can only be given one
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let first = stdout.lines().next().unwrap_or_default();
+
+    Url::parse(first.trim()).map_err(ResolveUrlError::ParseUrl)
+}
+
+/// Why yt-dlp failed, as reported by the `ERROR:` lines in its stderr
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum YtDlpError {
+    #[error("unsupported url")]
+    UnsupportedUrl,
+    #[error("media is private")]
+    Private,
+    #[error("media is not available in this country")]
+    GeoBlocked,
+    #[error("media is age restricted")]
+    AgeRestricted,
+    #[error("media has been removed")]
+    Removed,
+    #[error("rate limited by site")]
+    RateLimited,
+    #[error("network error: {0}")]
+    Network(String),
+    #[error("{0}")]
+    Other(String),
+    #[error("yt-dlp exited without reporting an error")]
+    Unknown,
+}
+
+impl YtDlpError {
+    pub fn from_stderr(stderr: &str) -> Self {
+        // yt-dlp may print several errors, the last is the one it gave up on
+        let message = stderr.lines()
+            .rev()
+            .find_map(|line| line.trim().strip_prefix("ERROR:"));
+
+        match message {
+            Some(message) => YtDlpError::classify(message.trim()),
+            None => YtDlpError::Unknown,
+        }
+    }
+
+    fn classify(message: &str) -> Self {
+        lazy_static!{
+            static ref NETWORK: Regex = Regex::new(
+                r"HTTP Error 5\d\d|timed out|[Cc]onnection (reset|refused|aborted)|[Tt]emporary failure in name resolution").unwrap();
+        }
+
+        let contains = |patterns: &[&str]| {
+            patterns.iter().any(|pattern| message.contains(pattern))
+        };
+
+        if contains(&["Unsupported URL", "is not a valid URL"]) {
+            YtDlpError::UnsupportedUrl
+        } else if contains(&["Private video", "video is private", "playlist is private"]) {
+            YtDlpError::Private
+        } else if contains(&["available in your country", "geo restriction", "geo-restricted"]) {
+            YtDlpError::GeoBlocked
+        } else if contains(&["confirm your age", "age-restricted", "inappropriate for some users"]) {
+            YtDlpError::AgeRestricted
+        } else if contains(&["has been removed", "Video unavailable", "no longer available", "has been terminated", "HTTP Error 404", "HTTP Error 410"]) {
+            YtDlpError::Removed
+        } else if contains(&["HTTP Error 429", "Too Many Requests", "rate-limit", "rate limit"]) {
+            YtDlpError::RateLimited
+        } else if NETWORK.is_match(message) {
+            YtDlpError::Network(message.to_owned())
+        } else {
+            YtDlpError::Other(message.to_owned())
+        }
+    }
+
+    /// Whether trying again later might succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            YtDlpError::RateLimited | YtDlpError::Network(_) | YtDlpError::Unknown => true,
+            YtDlpError::UnsupportedUrl
+            | YtDlpError::Private
+            | YtDlpError::GeoBlocked
+            | YtDlpError::AgeRestricted
+            | YtDlpError::Removed
+            | YtDlpError::Other(_) => false,
+        }
+    }
+}
+
+fn parse_info(json: &str) -> Result<Info, serde_json::Error> {
+    let value = serde_json::from_str::<serde_json::Value>(json)?;
+
+    match value.get("_type").and_then(|ty| ty.as_str()) {
+        Some("playlist") => Ok(Info::Playlist(serde_json::from_value(value)?)),
+        _ => Ok(Info::Single(serde_json::from_value(value)?)),
+    }
 }
 
 async fn read_to_string_logging_errors(stream: &str, mut read: impl AsyncRead + Unpin) -> String {
@@ -93,17 +344,36 @@ async fn read_to_string_logging_errors(stream: &str, mut read: impl AsyncRead +
 }
 
 pub struct DownloadHandle {
-    pub file: SharedFile,
+    pub dir: SharedDir,
+    /// Changes once yt-dlp has extracted the audio into a file of its own
+    pub file: watch::Receiver<SharedFile>,
     pub thumbnail: Option<SharedFile>,
+    /// Subtitles in the first language yt-dlp found, if any
+    pub subtitles: Option<SharedFile>,
+    /// Subtitles in any other languages, kept so that they're cleaned up
+    /// along with the rest of the download
+    pub other_subtitles: Vec<SharedFile>,
     pub metadata: Metadata,
     pub metadata_file: SharedFile,
     pub progress: watch::Receiver<Progress>,
     pub complete: Shared<oneshot::Receiver<Result<(), DownloadError>>>,
+    pub(crate) cancel: CancellationToken,
 }
 
 impl DownloadHandle {
+    /// Kills yt-dlp if it is still running. `complete` resolves with
+    /// `DownloadError::Cancelled` once the process has exited.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn file(&self) -> SharedFile {
+        self.file.borrow().clone()
+    }
+
     pub fn filename(&self) -> String {
-        let filename = self.file.path().file_name()
+        let file = self.file();
+        let filename = file.path().file_name()
             .expect("download path always has filename");
 
         filename.to_string_lossy().to_string()
@@ -113,37 +383,79 @@ impl DownloadHandle {
 #[derive(Clone)]
 pub struct Progress {
     pub downloaded_bytes: u64,
-    pub total_bytes: u64,
+    /// Exact size of the download. Some extractors (eg. HLS) don't know
+    /// this until the download has finished
+    pub total_bytes: Option<u64>,
+    /// yt-dlp's guess at the size when it doesn't know the exact size
+    pub total_bytes_estimate: Option<u64>,
 }
 
 impl Progress {
     pub fn complete(&self) -> bool {
-        self.downloaded_bytes == self.total_bytes
+        self.total_bytes == Some(self.downloaded_bytes)
+    }
+
+    /// The exact size if known, otherwise the estimate
+    pub fn expected_bytes(&self) -> Option<u64> {
+        self.total_bytes.or(self.total_bytes_estimate)
     }
 }
 
 #[derive(Debug, Error, Clone)]
 pub enum DownloadError {
+    #[error("creating working directory: {0}")]
+    WorkingDir(IoError),
     #[error("spawning yt-dlp command: {0}")]
     Spawn(IoError),
     #[error("reading from yt-dlp: {0}")]
     Read(IoError),
     #[error("reading from yt-dlp: {0}")]
     YtDlp(&'static str),
-    #[error("command failed")]
-    CommandError,
+    #[error("yt-dlp failed: {0}")]
+    CommandError(YtDlpError),
+    #[error("http request failed: {0}")]
+    Http(HttpError),
+    #[error("download ended after {downloaded} of {expected} bytes")]
+    Incomplete { downloaded: u64, expected: u64 },
+    #[error("writing download: {0}")]
+    Write(IoError),
     #[error("reading metadata: {0}")]
     ReadMetadata(IoError),
     #[error("parsing metadata: {0}")]
     ParseMetadata(MetadataParseError),
+    #[error("download cancelled")]
+    Cancelled,
+    /// Failure recorded by an earlier run of the server
+    #[error("{0}")]
+    Recorded(String),
 }
 
 impl DownloadError {
-    pub fn unexpected_eof() -> Self {
-        Self::read(io::Error::new(
-            std::io::ErrorKind::UnexpectedEof,
-            "yt-dlp terminated abruptly",
-        ))
+    pub fn working_dir(e: io::Error) -> Self {
+        DownloadError::WorkingDir(e.into())
+    }
+
+    /// Whether trying again later might succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            DownloadError::Read(_) => true,
+            DownloadError::CommandError(e) => e.is_transient(),
+            DownloadError::Http(HttpError(e)) => {
+                let status = e.status();
+                status.is_none() || status.is_some_and(|status| {
+                    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                })
+            }
+            DownloadError::Incomplete { .. } => true,
+            DownloadError::WorkingDir(_)
+            | DownloadError::Spawn(_)
+            | DownloadError::YtDlp(_)
+            | DownloadError::Write(_)
+            | DownloadError::ReadMetadata(_)
+            | DownloadError::ParseMetadata(_)
+            | DownloadError::Cancelled
+            | DownloadError::Recorded(_) => false,
+        }
     }
 
     pub fn spawn(e: io::Error) -> Self {
@@ -154,6 +466,14 @@ impl DownloadError {
         DownloadError::Read(e.into())
     }
 
+    pub fn http(e: reqwest::Error) -> Self {
+        DownloadError::Http(e.into())
+    }
+
+    pub fn write(e: io::Error) -> Self {
+        DownloadError::Write(e.into())
+    }
+
     pub fn read_metadata(e: io::Error) -> Self {
         DownloadError::ReadMetadata(e.into())
     }
@@ -173,38 +493,143 @@ pub struct IoError(Arc<io::Error>);
 #[from(types(serde_json::Error))]
 pub struct MetadataParseError(Arc<serde_json::Error>);
 
-pub async fn start_download(dir: SharedDir, url: &Url) -> Result<DownloadHandle, DownloadError> {
-    let mut process = Command::new("yt-dlp")
+#[derive(Debug, Error, Clone, From)]
+#[error("{0}")]
+#[from(types(reqwest::Error))]
+pub struct HttpError(Arc<reqwest::Error>);
+
+pub async fn start_download(
+    config: &config::YtDlp,
+    dir: SharedDir,
+    url: &Url,
+    clip: Clip,
+    processing: &Processing,
+) -> Result<DownloadHandle, DownloadError> {
+    let options = config.options_for(url);
+    let mut command = command(config, &options);
+
+    if let Some(sections) = clip.download_sections() {
+        command.arg("--download-sections").arg(sections);
+    }
+
+    if let Some(format) = &options.format {
+        command.arg("--format").arg(format);
+    }
+
+    if let Some(audio_format) = &options.audio_format {
+        command.arg("--audio-format").arg(audio_format);
+    }
+
+    if let Some(rate_limit) = &options.rate_limit {
+        command.arg("--limit-rate").arg(rate_limit);
+    }
+
+    let sponsorblock_remove = processing.sponsorblock_remove(&options);
+
+    if let Some(categories) = sponsorblock_remove {
+        command.arg("--sponsorblock-remove").arg(categories.join(","));
+    }
+
+    // subtitles become lyrics, automatic captions will do when there's
+    // nothing better. these are the formats we can read. subtitles stay
+    // timed to the media from before any SponsorBlock segments were cut
+    // out of it though, so those go without
+    let write_subs = sponsorblock_remove.is_none()
+        && options.subtitle_langs.as_ref().is_none_or(|langs| !langs.is_empty());
+
+    if write_subs {
+        command
+            .arg("--write-subs")
+            .arg("--write-auto-subs")
+            .arg("--sub-format").arg("vtt/srt");
+
+        if let Some(langs) = &options.subtitle_langs {
+            // live chat replays count as subtitles too
+            command.arg("--sub-langs").arg(format!("{},-live_chat", langs.join(",")));
+        }
+    }
+
+    let audio_quality = options.audio_quality.as_deref().unwrap_or("0"); // best
+
+    let mut process = command
         .arg("--extract-audio")
-        .arg("--audio-quality=0") // best
+        .arg(format!("--audio-quality={audio_quality}"))
         .arg("--no-overwrites")
         .arg("--no-part")
         .arg("--write-info-json")
         .arg("--write-thumbnail")
         .arg("--newline") // output progress updates as newlines
-        .arg("--progress-template=download:hailsplay-progress:D=%(progress.downloaded_bytes)s:T=%(progress.total_bytes)s")
+        .arg("--progress-template=download:hailsplay-progress:D=%(progress.downloaded_bytes)s:T=%(progress.total_bytes)s:E=%(progress.total_bytes_estimate)s")
         .arg(url.to_string())
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .current_dir(dir.path())
         .kill_on_drop(true)
         .spawn()
         .map_err(DownloadError::spawn)?;
 
     let stdout = process.stdout.take().unwrap();
+    let stderr = process.stderr.take().unwrap();
+
     let mut ytdlp = YtdlpReader {
         process,
         reader: BufReader::new(stdout),
+        stderr: Some(tokio::task::spawn(capture_stderr(stderr))),
     };
 
+    let Header { file, thumbnail, subtitles, metadata_file, progress } =
+        read_header(&mut ytdlp, &dir).await?;
+
+    let metadata_json = tokio::fs::read_to_string(metadata_file.path()).await
+        .map_err(DownloadError::read_metadata)?;
+
+    let metadata = serde_json::from_str::<Metadata>(&metadata_json)
+        .map_err(DownloadError::parse_metadata)?;
+
+    let (file_tx, file_rx) = watch::channel(file.into_shared());
+    let (progress_tx, progress_rx) = watch::channel(progress);
+    let cancel = CancellationToken::new();
+
+    let download = run_download(ytdlp, dir.clone(), file_tx, progress_tx, cancel.clone());
+
+    let mut subtitles = subtitles.into_iter().map(|subs| subs.into_shared());
+
+    Ok(DownloadHandle {
+        dir,
+        file: file_rx,
+        thumbnail: thumbnail.map(|th| th.into_shared()),
+        subtitles: subtitles.next(),
+        other_subtitles: subtitles.collect(),
+        metadata: metadata,
+        metadata_file: metadata_file.into_shared(),
+        progress: progress_rx,
+        complete: spawn_download(download),
+        cancel,
+    })
+}
+
+/// The files yt-dlp reports before it starts downloading the media itself
+struct Header {
+    file: OwnedFile,
+    thumbnail: Option<OwnedFile>,
+    /// In the order yt-dlp wrote them
+    subtitles: Vec<OwnedFile>,
+    metadata_file: OwnedFile,
+    progress: Progress,
+}
+
+async fn read_header(ytdlp: &mut YtdlpReader, dir: &SharedDir) -> Result<Header, DownloadError> {
     let mut file = None;
     let mut thumbnail = None;
+    let mut subtitles = Vec::new();
+    let mut subtitle_filenames = Vec::new();
     let mut metadata = None;
     let mut progress = None;
 
     loop {
         let line = match ytdlp.read_line().await? {
             Some(line) => line,
-            None => { return Err(DownloadError::unexpected_eof()); }
+            None => { return Err(ytdlp.failure().await); }
         };
 
         match line {
@@ -216,12 +641,23 @@ pub async fn start_download(dir: SharedDir, url: &Url) -> Result<DownloadHandle,
                 log::debug!("yt-dlp reported metadata filename: {f}");
                 metadata = Some(dir.claim_external_file(Path::new(&f)));
             }
+            Line::Subtitles { filename: f } => {
+                log::debug!("yt-dlp reported subtitles filename: {f}");
+                subtitles.push(dir.claim_external_file(Path::new(&f)));
+                subtitle_filenames.push(f);
+            }
+            // subtitles are downloaded before the media and report their
+            // progress just the same, which tells us nothing
+            Line::Download { filename: f } if subtitle_filenames.contains(&f) => {}
+            Line::Progress(_) | Line::Complete if file.is_none() => {}
+            Line::ExtractAudio { .. } => {}
             Line::Download { filename: f } => {
                 log::debug!("yt-dlp reported download filename: {f}");
                 file = Some(dir.claim_external_file(Path::new(&f)));
             }
             Line::Progress(p) => {
-                log::debug!("yt-dlp reported total bytes: {}", p.total_bytes);
+                log::debug!("yt-dlp reported total bytes: {:?} (estimate: {:?})",
+                    p.total_bytes, p.total_bytes_estimate);
                 progress = Some(p);
                 break;
             }
@@ -244,29 +680,21 @@ pub async fn start_download(dir: SharedDir, url: &Url) -> Result<DownloadHandle,
         return Err(DownloadError::YtDlp("yt-dlp never started reporting progress"));
     };
 
-    let metadata_json = tokio::fs::read_to_string(metadata_file.path()).await
-        .map_err(DownloadError::read_metadata)?;
-
-    let metadata = serde_json::from_str::<Metadata>(&metadata_json)
-        .map_err(DownloadError::parse_metadata)?;
+    Ok(Header { file, thumbnail, subtitles, metadata_file, progress })
+}
 
-    let (progress_tx, progress_rx) = watch::channel(progress.clone());
+/// Runs a download in the background, resolving the returned future with
+/// its result. The download is dropped, stopping it, once nobody is
+/// waiting for the result anymore
+pub fn spawn_download(download: impl Future<Output = Result<(), DownloadError>> + Send + 'static)
+    -> Shared<oneshot::Receiver<Result<(), DownloadError>>>
+{
     let (complete_tx, complete_rx) = oneshot::channel();
 
-    let handle = DownloadHandle {
-        file: file.into_shared(),
-        thumbnail: thumbnail.map(|th| th.into_shared()),
-        metadata: metadata,
-        metadata_file: metadata_file.into_shared(),
-        progress: progress_rx,
-        complete: complete_rx.shared(),
-    };
-
     tokio::task::spawn(async move {
         let mut complete_tx = Some(complete_tx);
 
-        let download_fut = run_download(ytdlp, progress_tx, progress.total_bytes);
-        futures::pin_mut!(download_fut);
+        futures::pin_mut!(download);
 
         future::poll_fn(|cx| {
             if let Poll::Ready(()) = complete_tx.as_mut().unwrap().poll_closed(cx) {
@@ -274,7 +702,7 @@ pub async fn start_download(dir: SharedDir, url: &Url) -> Result<DownloadHandle,
                 return Poll::Ready(());
             }
 
-            if let Poll::Ready(result) = download_fut.poll_unpin(cx) {
+            if let Poll::Ready(result) = download.poll_unpin(cx) {
                 let complete_tx = complete_tx.take().unwrap();
                 let _ = complete_tx.send(result);
                 return Poll::Ready(());
@@ -284,36 +712,111 @@ pub async fn start_download(dir: SharedDir, url: &Url) -> Result<DownloadHandle,
         }).await
     });
 
-    Ok(handle)
+    complete_rx.shared()
 }
 
 async fn run_download(
     mut ytdlp: YtdlpReader,
+    dir: SharedDir,
+    file_tx: watch::Sender<SharedFile>,
     progress_tx: watch::Sender<Progress>,
-    total_bytes: u64,
+    cancel: CancellationToken,
 ) -> Result<(), DownloadError> {
+    let file_path = file_tx.borrow().path().to_owned();
+
+    let result = tokio::select! {
+        result = read_download(&mut ytdlp, &progress_tx, &file_path) => result,
+        () = cancel.cancelled() => Err(DownloadError::Cancelled),
+    };
+
+    let extracted = match result {
+        Ok(extracted) => extracted,
+        Err(e) => {
+            // make sure yt-dlp has exited before anyone cleans up after it
+            let _ = ytdlp.process.kill().await;
+            return Err(e);
+        }
+    };
+
+    let result = ytdlp.process.wait().await;
+
+    match result {
+        Ok(status) if status.success() => {}
+        _ => { return Err(ytdlp.failure().await); }
+    }
+
+    // yt-dlp deletes what it downloaded once it has extracted the audio
+    // into a new file, which is what we're serving from now on
+    if let Some(filename) = extracted.filter(|f| dir.path().join(f) != file_path) {
+        log::debug!("yt-dlp extracted audio to: {filename}");
+
+        let file = dir.claim_external_file(Path::new(&filename));
+
+        let total_bytes = tokio::fs::metadata(file.path()).await
+            .map_err(DownloadError::read)?
+            .len();
+
+        file_tx.send_replace(file.into_shared());
+
+        let _ = progress_tx.send(Progress {
+            downloaded_bytes: total_bytes,
+            total_bytes: Some(total_bytes),
+            total_bytes_estimate: None,
+        });
+    }
+
+    Ok(())
+}
+
+/// Follows the download through to the end, returning the filename audio
+/// was extracted to if yt-dlp had to convert it
+async fn read_download(
+    ytdlp: &mut YtdlpReader,
+    progress_tx: &watch::Sender<Progress>,
+    file_path: &Path,
+) -> Result<Option<String>, DownloadError> {
     loop {
         let Some(line) = ytdlp.read_line().await? else {
-            return Err(DownloadError::unexpected_eof());
+            return Err(ytdlp.failure().await);
         };
 
         match line {
             Line::Progress(progress) => {
-                let percent = (progress.downloaded_bytes as f64 / progress.total_bytes as f64) * 100.0;
-                log::debug!("yt-dlp download progress {:.1}%", percent);
+                match progress.expected_bytes() {
+                    Some(total) if total > 0 => {
+                        let percent = (progress.downloaded_bytes as f64 / total as f64) * 100.0;
+                        log::debug!("yt-dlp download progress {:.1}%", percent);
+                    }
+                    _ => {
+                        log::debug!("yt-dlp download progress {} bytes", progress.downloaded_bytes);
+                    }
+                }
 
-                let _ = progress_tx.send(progress.clone());
+                let complete = progress.complete();
+                let _ = progress_tx.send(progress);
 
-                if progress.downloaded_bytes == progress.total_bytes {
+                if complete {
                     break;
                 }
             }
             Line::Complete => {
                 log::debug!("yt-dlp download complete");
 
+                // when yt-dlp never knew the exact size, the file on
+                // disk is the only authority on how big it ended up
+                let known_total = progress_tx.borrow().total_bytes;
+
+                let total_bytes = match known_total {
+                    Some(total_bytes) => total_bytes,
+                    None => tokio::fs::metadata(file_path).await
+                        .map_err(DownloadError::read)?
+                        .len(),
+                };
+
                 let _ = progress_tx.send(Progress {
                     downloaded_bytes: total_bytes,
-                    total_bytes,
+                    total_bytes: Some(total_bytes),
+                    total_bytes_estimate: None,
                 });
 
                 break;
@@ -321,29 +824,73 @@ async fn run_download(
             | Line::Download { .. }
             | Line::Thumbnail { .. }
             | Line::Metadata { .. }
+            | Line::Subtitles { .. }
+            | Line::ExtractAudio { .. }
             | Line::Other { .. } => {}
         }
     }
 
-    // read any remaining lines
-    while let Some(_) = ytdlp.read_line().await? {
-        // pass
+    // read any remaining lines, audio is extracted after downloading
+    let mut extracted = None;
+
+    while let Some(line) = ytdlp.read_line().await? {
+        if let Line::ExtractAudio { filename } = line {
+            extracted = Some(filename);
+        }
     }
 
-    let result = ytdlp.process.wait().await;
+    Ok(extracted)
+}
 
-    match result {
-        Ok(status) if status.success() => Ok(()),
-        _ => Err(DownloadError::CommandError),
+// keeps the tail of yt-dlp's stderr, which is where it reports errors.
+// stderr must be drained continuously or yt-dlp blocks once the pipe fills
+async fn capture_stderr(stderr: ChildStderr) -> String {
+    const MAX_LINES: usize = 50;
+
+    let mut lines = BufReader::new(stderr).lines();
+    let mut tail = VecDeque::new();
+
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) => {
+                log::debug!("yt-dlp stderr: {line}");
+
+                if tail.len() == MAX_LINES {
+                    tail.pop_front();
+                }
+
+                tail.push_back(line);
+            }
+            Ok(None) => break,
+            Err(e) => {
+                log::warn!("error reading stderr of yt-dlp: {e:?}");
+                break;
+            }
+        }
     }
+
+    Vec::from(tail).join("\n")
 }
 
 struct YtdlpReader {
     process: Child,
     reader: BufReader<ChildStdout>,
+    stderr: Option<JoinHandle<String>>,
 }
 
 impl YtdlpReader {
+    /// Waits for yt-dlp to exit and works out why it failed from its stderr
+    pub async fn failure(&mut self) -> DownloadError {
+        let _ = self.process.wait().await;
+
+        let stderr = match self.stderr.take() {
+            Some(stderr) => stderr.await.unwrap_or_default(),
+            None => String::new(),
+        };
+
+        DownloadError::CommandError(YtDlpError::from_stderr(&stderr))
+    }
+
     pub async fn read_line(&mut self) -> Result<Option<Line>, DownloadError> {
         let mut line = String::new();
         match self.reader.read_line(&mut line).await {
@@ -362,7 +909,9 @@ impl YtdlpReader {
 enum Line {
     Thumbnail { filename: String },
     Metadata { filename: String },
+    Subtitles { filename: String },
     Download { filename: String },
+    ExtractAudio { filename: String },
     Progress(Progress),
     Complete,
     Other(String)
@@ -376,11 +925,17 @@ fn parse_line(line: &str) -> Line {
         static ref METADATA: Regex = Regex::new(
             r"^\[info\] Writing video metadata as JSON to: (.*)$").unwrap();
 
+        static ref SUBTITLES: Regex = Regex::new(
+            r"^\[info\] Writing video subtitles to: (.*)$").unwrap();
+
         static ref DOWNLOAD: Regex = Regex::new(
             r"^\[download\] Destination: (.*)$").unwrap();
 
+        static ref EXTRACT_AUDIO: Regex = Regex::new(
+            r"^\[ExtractAudio\] Destination: (.*)$").unwrap();
+
         static ref PROGRESS: Regex = Regex::new(
-            r"^hailsplay-progress:D=(\d+):T=(\d+)$").unwrap();
+            r"^hailsplay-progress:D=([^:]+):T=([^:]+):E=([^:]+)$").unwrap();
 
         static ref COMPLETE: Regex = Regex::new(
             r"^\[download\] 100%").unwrap();
@@ -394,16 +949,23 @@ fn parse_line(line: &str) -> Line {
         return Line::Metadata { filename: m.get(1).unwrap().as_str().to_owned() };
     }
 
+    if let Some(m) = SUBTITLES.captures(line) {
+        return Line::Subtitles { filename: m.get(1).unwrap().as_str().to_owned() };
+    }
+
     if let Some(m) = DOWNLOAD.captures(line) {
         return Line::Download { filename: m.get(1).unwrap().as_str().to_owned() };
     }
 
+    if let Some(m) = EXTRACT_AUDIO.captures(line) {
+        return Line::ExtractAudio { filename: m.get(1).unwrap().as_str().to_owned() };
+    }
+
     if let Some(m) = PROGRESS.captures(line) {
-        let downloaded_bytes: u64 = m.get(1).unwrap().as_str().parse().unwrap();
-        let total_bytes: u64 = m.get(2).unwrap().as_str().parse().unwrap();
         return Line::Progress(Progress {
-            downloaded_bytes,
-            total_bytes,
+            downloaded_bytes: parse_bytes(m.get(1).unwrap().as_str()).unwrap_or_default(),
+            total_bytes: parse_bytes(m.get(2).unwrap().as_str()),
+            total_bytes_estimate: parse_bytes(m.get(3).unwrap().as_str()),
         });
     }
 
@@ -413,3 +975,130 @@ fn parse_line(line: &str) -> Line {
 
     return Line::Other(line.to_owned());
 }
+
+// yt-dlp prints NA for fields it doesn't know, and estimates as floats
+fn parse_bytes(value: &str) -> Option<u64> {
+    if let Ok(bytes) = value.parse() {
+        return Some(bytes);
+    }
+
+    let bytes: f64 = value.parse().ok()?;
+
+    if bytes.is_finite() && bytes >= 0.0 {
+        Some(bytes as u64)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify() {
+        let cases = [
+            ("[generic] Unsupported URL: https://example.com/", YtDlpError::UnsupportedUrl),
+            ("'not a url' is not a valid URL. Set --default-search \"ytsearch\"", YtDlpError::UnsupportedUrl),
+            ("[youtube] abc: Private video. Sign in if you've been granted access to this video", YtDlpError::Private),
+            ("[youtube] abc: The uploader has not made this video available in your country", YtDlpError::GeoBlocked),
+            ("[youtube] abc: Sign in to confirm your age. This video may be inappropriate for some users.", YtDlpError::AgeRestricted),
+            ("[youtube] abc: Video unavailable. This video has been removed by the uploader", YtDlpError::Removed),
+            ("unable to download video data: HTTP Error 404: Not Found", YtDlpError::Removed),
+            ("unable to download video data: HTTP Error 429: Too Many Requests", YtDlpError::RateLimited),
+            ("[youtube] abc: Unable to download API page: HTTP Error 503: Service Unavailable",
+                YtDlpError::Network("[youtube] abc: Unable to download API page: HTTP Error 503: Service Unavailable".to_owned())),
+            ("unable to download video data: <urlopen error [Errno -3] Temporary failure in name resolution>",
+                YtDlpError::Network("unable to download video data: <urlopen error [Errno -3] Temporary failure in name resolution>".to_owned())),
+            ("Unable to download webpage: HTTP Error 403: Forbidden",
+                YtDlpError::Other("Unable to download webpage: HTTP Error 403: Forbidden".to_owned())),
+            ("something unexpected", YtDlpError::Other("something unexpected".to_owned())),
+        ];
+
+        for (message, expected) in cases {
+            assert_eq!(YtDlpError::classify(message), expected, "{message}");
+        }
+    }
+
+    #[test]
+    fn from_stderr() {
+        assert_eq!(YtDlpError::from_stderr(""), YtDlpError::Unknown);
+        assert_eq!(YtDlpError::from_stderr("WARNING: [youtube] falling back to generic n function search\n"),
+            YtDlpError::Unknown);
+
+        // yt-dlp gave up on the last of several errors
+        let stderr = "\
+ERROR: unable to download video data: HTTP Error 429: Too Many Requests
+WARNING: [youtube] abc: retrying
+ERROR: [youtube] abc: Video unavailable. This video has been removed by the uploader
+";
+        assert_eq!(YtDlpError::from_stderr(stderr), YtDlpError::Removed);
+
+        assert_eq!(YtDlpError::from_stderr("  ERROR:   something unexpected  \r\n"),
+            YtDlpError::Other("something unexpected".to_owned()));
+    }
+
+    #[test]
+    fn parse_bytes() {
+        assert_eq!(super::parse_bytes("1048576"), Some(1048576));
+        assert_eq!(super::parse_bytes("0"), Some(0));
+        assert_eq!(super::parse_bytes("18446744073709551615"), Some(u64::MAX));
+
+        // estimates come out as floats, rounded down
+        assert_eq!(super::parse_bytes("1048576.0"), Some(1048576));
+        assert_eq!(super::parse_bytes("3179315.5"), Some(3179315));
+
+        // unknown sizes
+        for value in ["NA", "", "-1.0", "inf", "NaN"] {
+            assert_eq!(super::parse_bytes(value), None, "{value}");
+        }
+    }
+
+    #[tokio::test]
+    async fn read_header_claims_every_subtitle_file() {
+        let dir = std::env::temp_dir().join(format!("hailsplay-test-{}", uuid::Uuid::new_v4()));
+        let dir = crate::fs::OwnedDir::create(dir).unwrap().into_shared();
+
+        for name in ["a.info.json", "a.en.vtt", "a.de.vtt", "a.webm"] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+
+        let output = "\
+[info] Writing video metadata as JSON to: a.info.json
+[info] Writing video subtitles to: a.en.vtt
+[download] Destination: a.en.vtt
+hailsplay-progress:D=100:T=100:E=NA
+[info] Writing video subtitles to: a.de.vtt
+[download] Destination: a.de.vtt
+hailsplay-progress:D=100:T=100:E=NA
+[download] Destination: a.webm
+hailsplay-progress:D=0:T=4096:E=NA
+";
+
+        let mut process = Command::new("printf").arg("%s").arg(output)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let mut ytdlp = YtdlpReader {
+            reader: BufReader::new(process.stdout.take().unwrap()),
+            process,
+            stderr: None,
+        };
+
+        let header = read_header(&mut ytdlp, &dir).await.unwrap();
+
+        let subtitles = header.subtitles.iter()
+            .map(|file| file.path().file_name().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(subtitles, ["a.en.vtt", "a.de.vtt"]);
+        assert_eq!(header.file.path().file_name().unwrap(), "a.webm");
+        assert_eq!(header.progress.total_bytes, Some(4096));
+
+        // everything yt-dlp wrote is claimed, so the directory goes with it
+        let path = dir.path().to_owned();
+        drop(header);
+        drop(dir);
+        assert!(!path.exists());
+    }
+}